@@ -0,0 +1,124 @@
+/*
+ * Copyright 2025 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Ways of changing the memory size of a VM.
+//!
+//! Guest statistics are always read from the balloon device, the backend
+//! only decides how a new memory size is applied.
+
+use crate::{
+    config::Backend,
+    qmp::{
+        commands::{
+            peripheral_path, query_virtio_mem_size, set_virtio_mem_requested_size, Balloon,
+        },
+        Event, QmpConnection,
+    },
+    stats::MemoryStats,
+};
+use anyhow::{ensure, Result};
+
+/// Balloon sizes within this distance of the target count as reached.
+pub(crate) const BALLOON_TOLERANCE: usize = 1024 * 1024;
+
+/// Properties of a virtio-mem device, read when connecting.
+#[derive(Debug)]
+pub struct VirtioMemDevice {
+    id: String,
+    qom_path: String,
+    block_size: usize,
+    max_size: usize,
+    /// Last `requested-size` set by us
+    requested: Option<usize>,
+}
+
+/// Backend of a connected VM.
+#[derive(Debug)]
+pub enum MemoryBackend {
+    Balloon,
+    VirtioMem(VirtioMemDevice),
+}
+
+impl MemoryBackend {
+    /// Sets up the backend on a newly established connection.
+    pub async fn connect(qmp: &QmpConnection, backend: &Backend) -> Result<Self> {
+        Ok(match backend {
+            Backend::Balloon => Self::Balloon,
+            Backend::VirtioMem(device) => {
+                let (block_size, max_size) = tokio::try_join!(
+                    qmp.execute(query_virtio_mem_size(device, "block-size")),
+                    qmp.execute(query_virtio_mem_size(device, "max-size")),
+                )?;
+                ensure!(
+                    block_size > 0,
+                    "virtio-mem device {device} has no block size"
+                );
+                Self::VirtioMem(VirtioMemDevice {
+                    id: device.clone(),
+                    qom_path: peripheral_path(device),
+                    block_size,
+                    max_size,
+                    requested: None,
+                })
+            }
+        })
+    }
+
+    /// Current memory size of the guest.
+    pub fn current_size(&self, stats: &MemoryStats) -> usize {
+        match self {
+            Self::Balloon => stats.balloon_size,
+            Self::VirtioMem(_) => stats.total_memory,
+        }
+    }
+
+    /// Largest memory size the backend can give to the guest.
+    pub fn max_size(&self, stats: &MemoryStats) -> usize {
+        match self {
+            Self::Balloon => stats.total_memory,
+            Self::VirtioMem(dev) => stats.base_memory + dev.max_size,
+        }
+    }
+
+    /// Requests the guest memory size to be changed to `target`.
+    ///
+    /// Returns the size actually requested, which may be rounded to what the
+    /// backend supports.
+    pub async fn resize(
+        &mut self,
+        qmp: &QmpConnection,
+        stats: &MemoryStats,
+        target: usize,
+    ) -> Result<usize> {
+        match self {
+            Self::Balloon => {
+                let target = target.min(stats.total_memory);
+                qmp.execute(Balloon { value: target }).await?;
+                Ok(target)
+            }
+            Self::VirtioMem(dev) => {
+                let plugged = target.saturating_sub(stats.base_memory).min(dev.max_size);
+                let plugged = plugged - plugged % dev.block_size;
+                qmp.execute(set_virtio_mem_requested_size(&dev.id, plugged))
+                    .await?;
+                dev.requested = Some(plugged);
+                Ok(stats.base_memory + plugged)
+            }
+        }
+    }
+
+    /// Returns `true` if `event` confirms that `target` was reached.
+    pub fn target_reached(&self, event: &Event, target: usize) -> bool {
+        match (self, event) {
+            (Self::Balloon, Event::BalloonChange { actual }) => {
+                actual.abs_diff(target) < BALLOON_TOLERANCE
+            }
+            (Self::VirtioMem(dev), Event::MemoryDeviceSizeChange { qom_path, size, .. }) => {
+                *qom_path == dev.qom_path && dev.requested == Some(*size)
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,749 @@
+/*
+ * Copyright 2025 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Settings of the managed VMs.
+//!
+//! The VMs are listed in a TOML file, settings not given for a VM fall back
+//! to the `[defaults]` table and then to built-in defaults:
+//!
+//! ```toml
+//! control = "/run/ghaf-mem-manager/control.sock"
+//! metrics = "127.0.0.1:9101"
+//! state = "/run/ghaf-mem-manager/state.json"
+//!
+//! [host]
+//! reserve = "1G"
+//!
+//! [record]
+//! path = "/var/log/ghaf-mem-manager/trace.csv"
+//! format = "csv"
+//! max-size = "16M"
+//!
+//! [shutdown]
+//! restore = true
+//!
+//! [defaults]
+//! low = 70
+//! high = 80
+//!
+//! [[vm]]
+//! name = "gui-vm"
+//! socket = "/run/gui-vm.qmp"
+//! minimum = "2G"
+//! priority = 10
+//!
+//! [[vm]]
+//! name = "chrome-vm"
+//! socket = "/run/chrome-vm.qmp"
+//! backend = { virtio-mem = "vmem0" }
+//! policy = "pid"
+//! target-pressure = 75
+//! max-step = "128M"
+//! ```
+
+use crate::metrics::Endpoint;
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Lists the given fields that differ between two configs as
+/// `field: old -> new`, using the names of the configuration file.
+macro_rules! changes {
+    ($old:expr, $new:expr, $($field:ident),+) => {{
+        let mut changes = vec![];
+        $(
+            if $old.$field != $new.$field {
+                changes.push(format!(
+                    "{}: {:?} -> {:?}",
+                    stringify!($field).replace('_', "-"),
+                    $old.$field,
+                    $new.$field
+                ));
+            }
+        )+
+        changes
+    }};
+}
+
+/// How the memory size of a VM is changed.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    /// Inflate and deflate the virtio-balloon device
+    #[default]
+    Balloon,
+    /// Plug and unplug memory through the virtio-mem device with this id
+    VirtioMem(String),
+}
+
+/// Algorithm deciding the memory size of a VM.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Policy {
+    /// Inflate below the low and deflate above the high pressure limit
+    #[default]
+    Threshold,
+    /// Steer towards the target pressure with a PID controller
+    Pid,
+    /// Like threshold, but deflate early when usage is climbing quickly
+    Trend,
+}
+
+/// File format of recorded samples.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum RecordFormat {
+    /// One JSON object per line
+    #[default]
+    JsonLines,
+    /// Comma-separated values with a header line
+    Csv,
+}
+
+/// How a single VM is managed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmConfig {
+    /// Identity of the VM, used in logs and to key its state
+    pub name: String,
+    /// Path to the QMP socket
+    pub socket: PathBuf,
+    /// How the memory size is changed
+    pub backend: Backend,
+    /// How the memory size is decided
+    pub policy: Policy,
+    /// Monitoring interval
+    pub interval: Duration,
+    /// Minimum time between balloon changes
+    pub balloon_interval: Duration,
+    /// Timeout for QMP commands
+    pub qmp_timeout: Duration,
+    /// Age after which guest stats count as lost
+    pub stats_timeout: Duration,
+    /// Minimum memory size, also the floor the VM is shrunk to when host
+    /// memory is critical unless the guest uses more
+    pub minimum: usize,
+    /// Maximum memory size
+    pub maximum: usize,
+    /// Memory pressure below which the balloon is inflated
+    pub low: u8,
+    /// Memory pressure above which the balloon is deflated
+    pub high: u8,
+    /// Memory pressure the PID policy aims for
+    pub target_pressure: u8,
+    /// Proportional gain of the PID policy, in percent of the memory size
+    /// per percentage point of pressure error
+    pub pid_p: f32,
+    /// Integral gain of the PID policy, per sample
+    pub pid_i: f32,
+    /// Derivative gain of the PID policy, per sample
+    pub pid_d: f32,
+    /// Largest change of the memory size the PID policy makes at once
+    pub max_step: usize,
+    /// How far ahead the trend policy predicts memory usage
+    pub trend_horizon: Duration,
+    /// Weight of the VM when sharing host memory, VMs with a higher
+    /// priority get a larger share
+    pub priority: u32,
+    /// Only log the memory size changes instead of applying them
+    pub dry_run: bool,
+}
+
+/// Host-wide memory limits.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostConfig {
+    /// How often host memory is checked
+    pub interval: Duration,
+    /// Memory kept free for the host, VMs are not grown into it
+    pub reserve: usize,
+    /// Host memory below which VMs are shrunk right away, lowest priority
+    /// first and each down to its minimum before the next one is touched,
+    /// until the reserve is restored. VMs are never shrunk below the memory
+    /// their guest uses or 256 MiB.
+    pub critical: usize,
+    /// Host memory pressure (PSI `some avg10`) above which VMs are not grown
+    pub pressure_limit: f32,
+}
+
+/// Settings of a VM as given in the configuration file or on the command
+/// line. Settings that are not set fall back to the next level.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct VmSettings {
+    pub name: Option<String>,
+    pub socket: Option<PathBuf>,
+    pub backend: Option<Backend>,
+    pub policy: Option<Policy>,
+    /// Monitoring interval in seconds
+    pub interval: Option<u64>,
+    /// Minimum time between balloon changes in seconds
+    pub balloon_interval: Option<u64>,
+    /// Timeout for QMP commands in seconds
+    pub qmp_timeout: Option<u64>,
+    /// Age after which guest stats count as lost in seconds
+    pub stats_timeout: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub minimum: Option<usize>,
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub maximum: Option<usize>,
+    pub low: Option<u8>,
+    pub high: Option<u8>,
+    pub target_pressure: Option<u8>,
+    pub pid_p: Option<f32>,
+    pub pid_i: Option<f32>,
+    pub pid_d: Option<f32>,
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub max_step: Option<usize>,
+    /// Prediction horizon of the trend policy in seconds
+    pub trend_horizon: Option<u64>,
+    pub priority: Option<u32>,
+    pub dry_run: Option<bool>,
+}
+
+impl VmSettings {
+    /// Fills the settings missing from `self` from `fallback`.
+    pub fn or(self, fallback: &Self) -> Self {
+        let fallback = fallback.clone();
+        Self {
+            name: self.name.or(fallback.name),
+            socket: self.socket.or(fallback.socket),
+            backend: self.backend.or(fallback.backend),
+            policy: self.policy.or(fallback.policy),
+            interval: self.interval.or(fallback.interval),
+            balloon_interval: self.balloon_interval.or(fallback.balloon_interval),
+            qmp_timeout: self.qmp_timeout.or(fallback.qmp_timeout),
+            stats_timeout: self.stats_timeout.or(fallback.stats_timeout),
+            minimum: self.minimum.or(fallback.minimum),
+            maximum: self.maximum.or(fallback.maximum),
+            low: self.low.or(fallback.low),
+            high: self.high.or(fallback.high),
+            target_pressure: self.target_pressure.or(fallback.target_pressure),
+            pid_p: self.pid_p.or(fallback.pid_p),
+            pid_i: self.pid_i.or(fallback.pid_i),
+            pid_d: self.pid_d.or(fallback.pid_d),
+            max_step: self.max_step.or(fallback.max_step),
+            trend_horizon: self.trend_horizon.or(fallback.trend_horizon),
+            priority: self.priority.or(fallback.priority),
+            dry_run: self.dry_run.or(fallback.dry_run),
+        }
+    }
+
+    fn resolve(self) -> Result<VmConfig> {
+        let socket = self.socket.context("No socket given")?;
+        let interval = self.interval.unwrap_or(1);
+        let config = VmConfig {
+            name: self.name.unwrap_or_else(|| socket.display().to_string()),
+            socket,
+            backend: self.backend.unwrap_or_default(),
+            policy: self.policy.unwrap_or_default(),
+            interval: Duration::from_secs(interval),
+            balloon_interval: Duration::from_secs(self.balloon_interval.unwrap_or(3)),
+            qmp_timeout: Duration::from_secs(self.qmp_timeout.unwrap_or(5)),
+            // A few missed samples are fine
+            stats_timeout: Duration::from_secs(
+                self.stats_timeout
+                    .unwrap_or(10.max(interval.saturating_mul(3))),
+            ),
+            minimum: self.minimum.unwrap_or(usize::MIN),
+            maximum: self.maximum.unwrap_or(usize::MAX),
+            low: self.low.unwrap_or(70),
+            high: self.high.unwrap_or(80),
+            target_pressure: self.target_pressure.unwrap_or(75),
+            pid_p: self.pid_p.unwrap_or(0.5),
+            pid_i: self.pid_i.unwrap_or(0.05),
+            pid_d: self.pid_d.unwrap_or(0.2),
+            max_step: self.max_step.unwrap_or(256 * 1024 * 1024),
+            trend_horizon: Duration::from_secs(self.trend_horizon.unwrap_or(10)),
+            priority: self.priority.unwrap_or(1),
+            dry_run: self.dry_run.unwrap_or(false),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl VmConfig {
+    fn validate(&self) -> Result<()> {
+        ensure!(!self.name.is_empty(), "Name must not be empty");
+        ensure!(!self.interval.is_zero(), "interval must be at least 1s");
+        ensure!(
+            !self.qmp_timeout.is_zero(),
+            "qmp-timeout must be at least 1s"
+        );
+        ensure!(
+            self.stats_timeout > self.interval,
+            "stats-timeout must be longer than interval"
+        );
+        ensure!(
+            self.minimum <= self.maximum,
+            "minimum ({}) must not be above maximum ({})",
+            self.minimum,
+            self.maximum
+        );
+        ensure!(
+            self.high <= 100,
+            "high ({}) must not be above 100",
+            self.high
+        );
+        // The deflate target is computed for a pressure of high - 2
+        ensure!(self.high > 2, "high ({}) must be above 2", self.high);
+        ensure!(
+            self.low > 0 && self.low < self.high,
+            "low ({}) must be above 0 and below high ({})",
+            self.low,
+            self.high
+        );
+        ensure!(
+            self.target_pressure > 0 && self.target_pressure < 100,
+            "target-pressure ({}) must be above 0 and below 100",
+            self.target_pressure
+        );
+        ensure!(
+            [self.pid_p, self.pid_i, self.pid_d]
+                .iter()
+                .all(|gain| gain.is_finite() && *gain >= 0.),
+            "PID gains must not be negative"
+        );
+        ensure!(self.max_step > 0, "max-step must not be 0");
+        ensure!(self.priority > 0, "priority must be at least 1");
+        if let Backend::VirtioMem(device) = &self.backend {
+            ensure!(!device.is_empty(), "virtio-mem device id must not be empty");
+        }
+        Ok(())
+    }
+
+    /// Describes the settings that differ in `new`, for logging.
+    pub fn changes(&self, new: &Self) -> Vec<String> {
+        changes!(
+            self,
+            new,
+            socket,
+            backend,
+            policy,
+            interval,
+            balloon_interval,
+            qmp_timeout,
+            stats_timeout,
+            minimum,
+            maximum,
+            low,
+            high,
+            target_pressure,
+            pid_p,
+            pid_i,
+            pid_d,
+            max_step,
+            trend_horizon,
+            priority,
+            dry_run
+        )
+    }
+
+    /// Returns `true` if `new` changes settings the policy is built from,
+    /// so that it has to start over.
+    pub fn policy_changed(&self, new: &Self) -> bool {
+        !changes!(
+            self,
+            new,
+            policy,
+            interval,
+            low,
+            high,
+            target_pressure,
+            pid_p,
+            pid_i,
+            pid_d,
+            max_step,
+            trend_horizon
+        )
+        .is_empty()
+    }
+}
+
+impl HostConfig {
+    /// Describes the settings that differ in `new`, for logging.
+    pub fn changes(&self, new: &Self) -> Vec<String> {
+        changes!(self, new, interval, reserve, critical, pressure_limit)
+    }
+}
+
+/// Host settings as given in the configuration file or on the command line.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct HostSettings {
+    /// How often host memory is checked in seconds
+    pub interval: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub reserve: Option<usize>,
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub critical: Option<usize>,
+    pub pressure_limit: Option<f32>,
+}
+
+impl HostSettings {
+    /// Fills the settings missing from `self` from `fallback`.
+    pub fn or(self, fallback: &Self) -> Self {
+        Self {
+            interval: self.interval.or(fallback.interval),
+            reserve: self.reserve.or(fallback.reserve),
+            critical: self.critical.or(fallback.critical),
+            pressure_limit: self.pressure_limit.or(fallback.pressure_limit),
+        }
+    }
+
+    fn resolve(self) -> Result<HostConfig> {
+        let config = HostConfig {
+            interval: Duration::from_secs(self.interval.unwrap_or(1)),
+            reserve: self.reserve.unwrap_or(512 * 1024 * 1024),
+            critical: self.critical.unwrap_or(256 * 1024 * 1024),
+            pressure_limit: self.pressure_limit.unwrap_or(20.),
+        };
+        ensure!(!config.interval.is_zero(), "interval must be at least 1s");
+        ensure!(
+            config.critical <= config.reserve,
+            "critical ({}) must not be above reserve ({})",
+            config.critical,
+            config.reserve
+        );
+        ensure!(
+            (0. ..=100.).contains(&config.pressure_limit),
+            "pressure-limit must be between 0 and 100"
+        );
+        Ok(config)
+    }
+}
+
+/// Where and how samples and decisions are recorded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordConfig {
+    pub path: PathBuf,
+    pub format: RecordFormat,
+    /// Size at which the file is rotated
+    pub max_size: usize,
+    /// Number of rotated files kept, as `path.1` to `path.<keep>`
+    pub keep: usize,
+}
+
+/// Recording settings as given in the `[record]` table.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RecordSettings {
+    /// Nothing is recorded without a path
+    pub path: Option<PathBuf>,
+    pub format: Option<RecordFormat>,
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub max_size: Option<usize>,
+    pub keep: Option<usize>,
+}
+
+impl RecordSettings {
+    /// Fills the settings missing from `self` from `fallback`.
+    pub fn or(self, fallback: &Self) -> Self {
+        Self {
+            path: self.path.or_else(|| fallback.path.clone()),
+            format: self.format.or(fallback.format),
+            max_size: self.max_size.or(fallback.max_size),
+            keep: self.keep.or(fallback.keep),
+        }
+    }
+
+    /// Returns `None` when recording is disabled.
+    pub fn resolve(self) -> Result<Option<RecordConfig>> {
+        let Some(path) = self.path else {
+            return Ok(None);
+        };
+        let config = RecordConfig {
+            path,
+            format: self.format.unwrap_or_default(),
+            max_size: self.max_size.unwrap_or(16 * 1024 * 1024),
+            keep: self.keep.unwrap_or(3),
+        };
+        ensure!(config.max_size > 0, "record max-size must not be zero");
+        Ok(Some(config))
+    }
+}
+
+/// What happens to the VMs when the manager exits.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShutdownConfig {
+    /// Return every VM to its maximum size before exiting
+    pub restore: bool,
+    /// How long to wait for the guests to confirm their new size
+    pub timeout: Duration,
+}
+
+/// Shutdown settings as given in the `[shutdown]` table.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ShutdownSettings {
+    pub restore: Option<bool>,
+    /// Timeout in seconds
+    pub timeout: Option<u64>,
+}
+
+impl ShutdownSettings {
+    /// Fills the settings missing from `self` from `fallback`.
+    pub fn or(self, fallback: &Self) -> Self {
+        Self {
+            restore: self.restore.or(fallback.restore),
+            timeout: self.timeout.or(fallback.timeout),
+        }
+    }
+
+    pub fn resolve(self) -> ShutdownConfig {
+        ShutdownConfig {
+            restore: self.restore.unwrap_or(false),
+            timeout: Duration::from_secs(self.timeout.unwrap_or(10)),
+        }
+    }
+}
+
+/// Contents of the configuration file.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Path of the control socket, see [`crate::control`]
+    pub control: Option<PathBuf>,
+    /// Where to serve metrics, see [`crate::metrics`]
+    pub metrics: Option<Endpoint>,
+    /// File keeping the state across restarts, see [`crate::state`]
+    pub state: Option<PathBuf>,
+    #[serde(default)]
+    pub host: HostSettings,
+    /// Recording of samples and decisions, see [`crate::recorder`]
+    #[serde(default)]
+    pub record: RecordSettings,
+    #[serde(default)]
+    pub shutdown: ShutdownSettings,
+    /// Settings applied to every VM that does not set them itself
+    #[serde(default)]
+    pub defaults: VmSettings,
+    #[serde(default)]
+    pub vm: Vec<VmSettings>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid configuration in {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let config: Self = toml::from_str(text)?;
+        ensure!(
+            config.defaults.name.is_none() && config.defaults.socket.is_none(),
+            "[defaults] cannot set name or socket"
+        );
+        Ok(config)
+    }
+
+    /// Resolves the settings of the VMs and the host.
+    ///
+    /// `vm_overrides` and `host_overrides`, e.g. from the command line, take
+    /// precedence over everything in the file.
+    pub fn resolve(
+        &self,
+        vm_overrides: &VmSettings,
+        host_overrides: &HostSettings,
+    ) -> Result<(Vec<VmConfig>, HostConfig)> {
+        let mut names = HashSet::new();
+        let mut sockets = HashSet::new();
+        let mut vms = vec![];
+        for (i, vm) in self.vm.iter().enumerate() {
+            let label = vm.name.clone().unwrap_or_else(|| format!("#{}", i + 1));
+            let config = VmSettings {
+                name: None,
+                socket: None,
+                ..vm_overrides.clone()
+            }
+            .or(vm)
+            .or(&self.defaults)
+            .resolve()
+            .with_context(|| format!("Invalid settings for VM {label}"))?;
+            if !names.insert(config.name.clone()) {
+                bail!("VM {} is listed more than once", config.name);
+            }
+            if !sockets.insert(config.socket.clone()) {
+                bail!(
+                    "Socket {} is used by more than one VM",
+                    config.socket.display()
+                );
+            }
+            vms.push(config);
+        }
+        ensure!(!vms.is_empty(), "No VMs to manage");
+        let host = host_overrides
+            .clone()
+            .or(&self.host)
+            .resolve()
+            .context("Invalid host settings")?;
+        Ok((vms, host))
+    }
+
+    /// Resolves the settings of the VM `name` without connecting to it, e.g.
+    /// to simulate it. A VM not listed in the file gets the defaults.
+    pub fn resolve_vm(&self, name: &str, overrides: &VmSettings) -> Result<VmConfig> {
+        let listed = self
+            .vm
+            .iter()
+            .find(|vm| vm.name.as_deref() == Some(name))
+            .cloned()
+            .unwrap_or_default();
+        VmSettings {
+            name: Some(name.to_owned()),
+            // Never connected to
+            socket: Some(listed.socket.clone().unwrap_or_default()),
+            ..overrides.clone()
+        }
+        .or(&listed)
+        .or(&self.defaults)
+        .resolve()
+        .with_context(|| format!("Invalid settings for VM {name}"))
+    }
+}
+
+/// Parses a memory size in bytes, optionally with a `K`, `M`, `G` or `T`
+/// suffix for binary units.
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let (digits, shift) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 30),
+        Some((i, 'T' | 't')) => (&s[..i], 40),
+        _ => (s, 0),
+    };
+    digits
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("invalid size '{s}'"))
+}
+
+pub(crate) fn deserialize_size<'de, D: Deserializer<'de>>(d: D) -> Result<Option<usize>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(usize),
+        Text(String),
+    }
+    Ok(match Size::deserialize(d)? {
+        Size::Bytes(n) => Some(n),
+        Size::Text(s) => Some(parse_size(&s).map_err(serde::de::Error::custom)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = r#"
+        [host]
+        reserve = "1G"
+
+        [defaults]
+        low = 60
+        high = 75
+
+        [[vm]]
+        name = "gui-vm"
+        socket = "/run/gui-vm.qmp"
+        minimum = "2G"
+        priority = 10
+
+        [[vm]]
+        name = "chrome-vm"
+        socket = "/run/chrome-vm.qmp"
+        backend = { virtio-mem = "vmem0" }
+        high = 90
+    "#;
+
+    #[test]
+    fn settings_fall_back_to_defaults() {
+        let (vms, host) = Config::parse(EXAMPLE)
+            .unwrap()
+            .resolve(&VmSettings::default(), &HostSettings::default())
+            .unwrap();
+        assert_eq!(host.reserve, 1 << 30);
+        assert_eq!(vms[0].name, "gui-vm");
+        assert_eq!(vms[0].minimum, 2 << 30);
+        assert_eq!((vms[0].low, vms[0].high, vms[0].priority), (60, 75, 10));
+        assert_eq!(vms[0].backend, Backend::Balloon);
+        assert_eq!((vms[1].low, vms[1].high, vms[1].priority), (60, 90, 1));
+        assert_eq!(vms[1].backend, Backend::VirtioMem("vmem0".into()));
+        assert_eq!(vms[1].balloon_interval, Duration::from_secs(3));
+    }
+
+    #[test]
+    fn overrides_take_precedence() {
+        let overrides = VmSettings {
+            high: Some(85),
+            ..Default::default()
+        };
+        let (vms, _) = Config::parse(EXAMPLE)
+            .unwrap()
+            .resolve(&overrides, &HostSettings::default())
+            .unwrap();
+        assert!(vms.iter().all(|vm| vm.high == 85));
+        assert_eq!(vms[0].low, 60);
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        let resolve = |text: &str| {
+            Config::parse(text)?.resolve(&VmSettings::default(), &HostSettings::default())
+        };
+        assert!(resolve("").is_err());
+        assert!(resolve("[[vm]]\nsocket = \"/a\"\nlimit = 3").is_err());
+        assert!(resolve("[[vm]]\nname = \"a\"").is_err());
+        let err = resolve("[[vm]]\nname = \"a\"\nsocket = \"/a\"\nlow = 90").unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "Invalid settings for VM a: low (90) must be above 0 and below high (80)"
+        );
+        assert!(resolve("[[vm]]\nsocket = \"/a\"\n[[vm]]\nsocket = \"/a\"").is_err());
+        assert!(resolve("[defaults]\nsocket = \"/a\"\n[[vm]]\nsocket = \"/b\"").is_err());
+    }
+
+    #[test]
+    fn changes_are_listed() {
+        let (vms, _) = Config::parse(EXAMPLE)
+            .unwrap()
+            .resolve(&VmSettings::default(), &HostSettings::default())
+            .unwrap();
+        let new = VmConfig {
+            high: 85,
+            interval: Duration::from_secs(2),
+            ..vms[0].clone()
+        };
+        assert!(vms[0].changes(&vms[0]).is_empty());
+        assert_eq!(
+            vms[0].changes(&new),
+            vec!["interval: 1s -> 2s", "high: 75 -> 85"]
+        );
+        assert!(vms[0].policy_changed(&new));
+        let new = VmConfig {
+            priority: 5,
+            maximum: 8 << 30,
+            ..vms[0].clone()
+        };
+        assert!(!vms[0].policy_changed(&new));
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert_eq!(parse_size("4K"), Ok(4096));
+        assert_eq!(parse_size("512M"), Ok(512 << 20));
+        assert_eq!(parse_size("2g"), Ok(2 << 30));
+        assert!(parse_size("2X").is_err());
+        assert!(parse_size("").is_err());
+    }
+}
@@ -0,0 +1,327 @@
+/*
+ * Copyright 2025 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Control interface of a running manager.
+//!
+//! JSON-RPC 2.0 on a Unix socket, one request or response per line. Every
+//! request gets a response. Methods:
+//!
+//! - `list`: status of all VMs, with their stats, pressure and target
+//! - `pause`, `resume` `{"vm": name}`: suspend or resume management of a VM
+//! - `pin` `{"vm": name, "size": size}`: keep a VM at a fixed size, given in
+//!   bytes or with a unit, e.g. `"2G"`, within the minimum and maximum of
+//!   the VM
+//! - `unpin` `{"vm": name}`: return a pinned VM to its policy
+//! - `evaluate` `{"vm": name}`: decide and apply a new size right away, for
+//!   all VMs if `vm` is left out
+//!
+//! ```text
+//! --> {"jsonrpc": "2.0", "method": "pin", "params": {"vm": "gui-vm", "size": "4G"}, "id": 1}
+//! <-- {"jsonrpc": "2.0", "result": null, "id": 1}
+//! ```
+
+use crate::{
+    config::deserialize_size,
+    manager::{ManagerHandle, VmCommand, VmStatus},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    fs::Permissions,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::Path,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+        UnixListener, UnixStream,
+    },
+};
+use tracing::{debug, info};
+
+/// Where clients look for the control socket unless told otherwise.
+pub const DEFAULT_SOCKET: &str = "/run/ghaf-mem-manager/control.sock";
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// Errors of the manager, e.g. an unknown VM
+const SERVER_ERROR: i64 = -32000;
+
+#[derive(Deserialize)]
+struct Request {
+    method: String,
+    #[serde(default)]
+    params: Value,
+    #[serde(default)]
+    id: Value,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Outcome {
+    Result(Value),
+    Error(RpcError),
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct Response {
+    jsonrpc: &'static str,
+    #[serde(flatten)]
+    outcome: Outcome,
+    id: Value,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VmParams {
+    vm: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PinParams {
+    vm: String,
+    #[serde(deserialize_with = "deserialize_size")]
+    size: Option<usize>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct EvaluateParams {
+    vm: Option<String>,
+}
+
+fn error(code: i64, message: impl ToString) -> RpcError {
+    RpcError {
+        code,
+        message: message.to_string(),
+    }
+}
+
+fn params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| error(INVALID_PARAMS, e))
+}
+
+/// Creates a Unix socket at `path` with the permissions `mode`.
+///
+/// A socket left behind by an earlier run is replaced, any other file at
+/// `path` is left alone and an error.
+pub(crate) fn bind_socket(path: &Path, mode: u32) -> Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => bail!("{} exists and is not a socket", path.display()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// Creates the control socket at `path`, replacing a socket left behind by
+/// an earlier run. Only the owner may connect, as clients can change VMs.
+pub fn bind(path: &Path) -> Result<UnixListener> {
+    let listener = bind_socket(path, 0o600)
+        .with_context(|| format!("Failed to bind control socket {}", path.display()))?;
+    info!("Control socket listening on {}", path.display());
+    Ok(listener)
+}
+
+/// Serves clients of the control socket until an error occurs.
+pub async fn serve(listener: UnixListener, manager: ManagerHandle) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let manager = manager.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_client(stream, &manager).await {
+                debug!("Control client failed: {e}");
+            }
+        });
+    }
+}
+
+async fn serve_client(stream: UnixStream, manager: &ManagerHandle) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut response = serde_json::to_vec(&handle_request(&line, manager).await)?;
+        response.push(b'\n');
+        writer.write_all(&response).await?;
+    }
+    Ok(())
+}
+
+async fn handle_request(line: &str, manager: &ManagerHandle) -> Response {
+    let (outcome, id) = match serde_json::from_str::<Value>(line) {
+        Err(e) => (Err(error(PARSE_ERROR, e)), Value::Null),
+        Ok(request) => match serde_json::from_value::<Request>(request) {
+            Err(e) => (Err(error(INVALID_REQUEST, e)), Value::Null),
+            Ok(request) => (
+                call(request.method, request.params, manager).await,
+                request.id,
+            ),
+        },
+    };
+    Response {
+        jsonrpc: "2.0",
+        outcome: match outcome {
+            Ok(result) => Outcome::Result(result),
+            Err(e) => Outcome::Error(e),
+        },
+        id,
+    }
+}
+
+async fn call(method: String, args: Value, manager: &ManagerHandle) -> Result<Value, RpcError> {
+    let (vm, command) = match method.as_str() {
+        "list" => {
+            let status = manager.status().await.map_err(|e| error(SERVER_ERROR, e))?;
+            return serde_json::to_value(status).map_err(|e| error(SERVER_ERROR, e));
+        }
+        "pause" => (Some(params::<VmParams>(args)?.vm), VmCommand::Pause),
+        "resume" => (Some(params::<VmParams>(args)?.vm), VmCommand::Resume),
+        "pin" => {
+            let PinParams { vm, size } = params(args)?;
+            (Some(vm), VmCommand::Pin(size))
+        }
+        "unpin" => (Some(params::<VmParams>(args)?.vm), VmCommand::Pin(None)),
+        "evaluate" => {
+            let EvaluateParams { vm } = if args.is_null() {
+                EvaluateParams::default()
+            } else {
+                params(args)?
+            };
+            (vm, VmCommand::Evaluate)
+        }
+        _ => return Err(error(METHOD_NOT_FOUND, format!("Unknown method {method}"))),
+    };
+    manager
+        .command(vm, command)
+        .await
+        .map_err(|e| error(SERVER_ERROR, format!("{e:#}")))?;
+    Ok(Value::Null)
+}
+
+/// Client of the control socket.
+pub struct ControlClient {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+    next_id: u64,
+}
+
+impl ControlClient {
+    pub async fn connect(path: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(path)
+            .await
+            .with_context(|| format!("Failed to connect to {}", path.display()))?;
+        let (reader, writer) = stream.into_split();
+        Ok(Self {
+            lines: BufReader::new(reader).lines(),
+            writer,
+            next_id: 0,
+        })
+    }
+
+    /// Calls `method` and waits for its result.
+    pub async fn call<T: DeserializeOwned>(&mut self, method: &str, params: Value) -> Result<T> {
+        #[derive(Deserialize)]
+        struct Reply {
+            result: Option<Value>,
+            error: Option<RemoteError>,
+        }
+        #[derive(Deserialize)]
+        struct RemoteError {
+            message: String,
+        }
+
+        self.next_id += 1;
+        let mut request = serde_json::to_vec(&json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params,
+            "id": self.next_id,
+        }))?;
+        request.push(b'\n');
+        self.writer.write_all(&request).await?;
+        let line = self
+            .lines
+            .next_line()
+            .await?
+            .ok_or_else(|| anyhow!("Connection closed by the manager"))?;
+        let reply: Reply = serde_json::from_str(&line)?;
+        if let Some(error) = reply.error {
+            bail!("{}", error.message);
+        }
+        Ok(serde_json::from_value(reply.result.unwrap_or_default())?)
+    }
+
+    pub async fn list(&mut self) -> Result<Vec<VmStatus>> {
+        self.call("list", Value::Null).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::HostConfig, manager::Manager};
+    use serde_json::json;
+    use std::time::Duration;
+
+    /// Requests that fail before they reach the manager.
+    #[tokio::test]
+    async fn invalid_requests() {
+        let host = HostConfig {
+            interval: Duration::from_secs(1),
+            reserve: 0,
+            critical: 0,
+            pressure_limit: 0.,
+        };
+        let manager = Manager::new(vec![], host).handle();
+        let code = |response: Response| match response.outcome {
+            Outcome::Error(e) => e.code,
+            Outcome::Result(_) => 0,
+        };
+        assert_eq!(code(handle_request("{", &manager).await), PARSE_ERROR);
+        assert_eq!(code(handle_request("[]", &manager).await), INVALID_REQUEST);
+        let response = handle_request(r#"{"method": "nope", "id": 3}"#, &manager).await;
+        assert_eq!(response.id, json!(3));
+        assert_eq!(code(response), METHOD_NOT_FOUND);
+        let pin = r#"{"method": "pin", "params": {"vm": "a", "size": "2X"}}"#;
+        assert_eq!(code(handle_request(pin, &manager).await), INVALID_PARAMS);
+        let pause = r#"{"method": "pause", "params": {}}"#;
+        assert_eq!(code(handle_request(pause, &manager).await), INVALID_PARAMS);
+    }
+
+    #[tokio::test]
+    async fn sockets_replace_only_sockets() {
+        let dir = std::env::temp_dir().join(format!("mem-manager-bind-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("control.sock");
+        drop(bind_socket(&path, 0o600).unwrap());
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // Left behind by an earlier run
+        drop(bind_socket(&path, 0o600).unwrap());
+
+        let file = dir.join("file");
+        std::fs::write(&file, "keep").unwrap();
+        assert!(bind_socket(&file, 0o600).is_err());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
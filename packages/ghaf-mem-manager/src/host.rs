@@ -0,0 +1,239 @@
+/*
+ * Copyright 2025 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Host memory accounting and arbitration between VMs.
+
+use crate::config::HostConfig;
+use anyhow::{Context, Result};
+use std::path::Path;
+
+/// Memory state of the host.
+#[derive(Debug, Clone, Default)]
+pub struct HostMemory {
+    pub total: usize,
+    pub free: usize,
+    pub available: usize,
+    /// `some avg10` of the host memory pressure stall information, if the
+    /// kernel provides it
+    pub pressure: Option<f32>,
+}
+
+impl HostMemory {
+    /// Reads the host memory state from `/proc`.
+    pub async fn read() -> Result<Self> {
+        Self::read_from(Path::new("/proc")).await
+    }
+
+    /// Reads the host memory state from a procfs mounted at `proc`.
+    pub async fn read_from(proc: &Path) -> Result<Self> {
+        let meminfo = tokio::fs::read_to_string(proc.join("meminfo"))
+            .await
+            .context("Failed to read meminfo")?;
+        let mut host = Self::parse_meminfo(&meminfo)?;
+        // PSI is optional in the kernel
+        host.pressure = tokio::fs::read_to_string(proc.join("pressure/memory"))
+            .await
+            .ok()
+            .and_then(|psi| Self::parse_pressure(&psi));
+        Ok(host)
+    }
+
+    fn parse_meminfo(meminfo: &str) -> Result<Self> {
+        let field = |name: &str| -> Result<usize> {
+            meminfo
+                .lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                .and_then(|value| value.trim().strip_suffix("kB"))
+                .and_then(|value| value.trim().parse::<usize>().ok())
+                .map(|kb| kb * 1024)
+                .with_context(|| format!("{name} missing from meminfo"))
+        };
+        Ok(Self {
+            total: field("MemTotal")?,
+            free: field("MemFree")?,
+            available: field("MemAvailable")?,
+            pressure: None,
+        })
+    }
+
+    fn parse_pressure(psi: &str) -> Option<f32> {
+        psi.lines()
+            .find_map(|line| line.strip_prefix("some "))?
+            .split_whitespace()
+            .find_map(|field| field.strip_prefix("avg10="))?
+            .parse()
+            .ok()
+    }
+}
+
+/// Decides how much memory the VMs may take from the host.
+#[derive(Debug, Clone)]
+pub struct Arbiter {
+    config: HostConfig,
+}
+
+impl Arbiter {
+    pub fn new(config: HostConfig) -> Self {
+        Self { config }
+    }
+
+    /// Memory the VMs may grow by in total, keeping the host reserve.
+    ///
+    /// Nothing is handed out while the host is already under memory pressure.
+    pub fn budget(&self, host: &HostMemory) -> usize {
+        if host
+            .pressure
+            .is_some_and(|pressure| pressure > self.config.pressure_limit)
+        {
+            return 0;
+        }
+        host.available.saturating_sub(self.config.reserve)
+    }
+
+    /// Returns `true` if host memory is below the critical watermark.
+    pub fn critical(&self, host: &HostMemory) -> bool {
+        host.available < self.config.critical
+    }
+
+    /// Memory missing to restore the host reserve.
+    pub fn deficit(&self, host: &HostMemory) -> usize {
+        self.config.reserve.saturating_sub(host.available)
+    }
+}
+
+/// Splits `budget` between demands of `(size, weight)`.
+///
+/// Each demand gets a share proportional to its weight, but never more than
+/// it asked for. What is left over by small demands is split again between
+/// the remaining ones.
+pub fn split(budget: usize, demands: &[(usize, u32)]) -> Vec<usize> {
+    let mut grants = vec![0; demands.len()];
+    let mut open: Vec<usize> = (0..demands.len()).filter(|&i| demands[i].0 > 0).collect();
+    let mut left = budget;
+    while left > 0 && !open.is_empty() {
+        // Wide enough for any size times any weight
+        let weights: u128 = open.iter().map(|&i| demands[i].1.max(1) as u128).sum();
+        let mut handed_out = 0;
+        open.retain(|&i| {
+            let share = (left as u128 * demands[i].1.max(1) as u128 / weights) as usize;
+            let grant = share.min(demands[i].0 - grants[i]);
+            grants[i] += grant;
+            handed_out += grant;
+            grants[i] < demands[i].0
+        });
+        if handed_out == 0 {
+            break;
+        }
+        left -= handed_out;
+    }
+    grants
+}
+
+/// Splits `budget` between demands of `(size, weight)` like [`split`], then
+/// hands out what is left by weight alone.
+///
+/// Every VM may grow a bit beyond its known demand, while all of them
+/// together never grow by more than `budget`.
+pub fn shares(budget: usize, demands: &[(usize, u32)]) -> Vec<usize> {
+    let grants = split(budget, demands);
+    let left = budget - grants.iter().sum::<usize>();
+    let unlimited: Vec<_> = demands
+        .iter()
+        .map(|&(_, weight)| (usize::MAX, weight))
+        .collect();
+    grants
+        .into_iter()
+        .zip(split(left, &unlimited))
+        .map(|(grant, spare)| grant + spare)
+        .collect()
+}
+
+/// Picks how far VMs of `(size, floor, priority)` are shrunk to free
+/// `deficit`, returning their new sizes.
+///
+/// VMs with the lowest priority are shrunk first, each down to its floor
+/// before the next one is touched.
+pub fn reclaim(deficit: usize, vms: &[(usize, usize, u32)]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..vms.len()).collect();
+    order.sort_by_key(|&i| vms[i].2);
+    let mut targets: Vec<usize> = vms.iter().map(|vm| vm.0).collect();
+    let mut left = deficit;
+    for i in order {
+        let (size, floor, _) = vms[i];
+        let take = size.saturating_sub(floor).min(left);
+        targets[i] = size - take;
+        left -= take;
+    }
+    targets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_meminfo() {
+        let host = HostMemory::parse_meminfo(
+            "MemTotal:        6158152 kB\n\
+             MemFree:         4047772 kB\n\
+             MemAvailable:    5648960 kB\n\
+             Buffers:          100000 kB\n",
+        )
+        .unwrap();
+        assert_eq!(host.total, 6158152 * 1024);
+        assert_eq!(host.free, 4047772 * 1024);
+        assert_eq!(host.available, 5648960 * 1024);
+        assert!(HostMemory::parse_meminfo("MemTotal: 1 kB\n").is_err());
+    }
+
+    #[test]
+    fn parse_pressure() {
+        assert_eq!(
+            HostMemory::parse_pressure(
+                "some avg10=12.50 avg60=1.00 avg300=0.00 total=10\n\
+                 full avg10=3.00 avg60=0.00 avg300=0.00 total=0\n"
+            ),
+            Some(12.5)
+        );
+        assert_eq!(HostMemory::parse_pressure(""), None);
+    }
+
+    #[test]
+    fn split_by_weight() {
+        // Enough for everyone
+        assert_eq!(split(100, &[(10, 1), (20, 1)]), vec![10, 20]);
+        // Proportional to weight
+        assert_eq!(split(90, &[(100, 1), (100, 2)]), vec![30, 60]);
+        // Leftovers of small demands go to the others
+        assert_eq!(split(90, &[(10, 1), (100, 1), (0, 5)]), vec![10, 80, 0]);
+        assert_eq!(split(0, &[(10, 1)]), vec![0]);
+        // Large sizes and weights do not overflow
+        assert_eq!(
+            split(
+                usize::MAX,
+                &[(usize::MAX, u32::MAX), (usize::MAX, u32::MAX)]
+            ),
+            vec![usize::MAX / 2; 2]
+        );
+    }
+
+    #[test]
+    fn shares_add_up_to_the_budget() {
+        // Demands first, the rest by weight
+        assert_eq!(shares(100, &[(10, 1), (20, 1)]), vec![45, 55]);
+        assert_eq!(shares(90, &[(0, 1), (0, 2)]), vec![30, 60]);
+        // Short, like split
+        assert_eq!(shares(90, &[(100, 1), (100, 2)]), vec![30, 60]);
+    }
+
+    #[test]
+    fn reclaim_by_priority() {
+        let vms = [(100, 40, 10), (100, 50, 1), (100, 20, 5)];
+        assert_eq!(reclaim(30, &vms), vec![100, 70, 100]);
+        assert_eq!(reclaim(100, &vms), vec![100, 50, 50]);
+        // Never below the floors
+        assert_eq!(reclaim(1000, &vms), vec![40, 50, 20]);
+    }
+}
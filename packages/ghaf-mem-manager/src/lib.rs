@@ -0,0 +1,23 @@
+/*
+ * Copyright 2025 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Library part of the Ghaf memory manager.
+//!
+//! The [`qmp`] module is a small asynchronous QEMU Machine Protocol client
+//! that other host daemons can use to talk to VMs. The [`manager`] module
+//! runs the memory management of a set of VMs on top of it.
+
+pub mod backend;
+pub mod config;
+pub mod control;
+pub mod host;
+pub mod manager;
+pub mod metrics;
+pub mod policy;
+pub mod qmp;
+pub mod recorder;
+pub mod simulate;
+pub mod state;
+pub mod stats;
@@ -3,385 +3,553 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
-use anyhow::{bail, Context, Result};
-use clap::Parser;
-use serde::{Deserialize, Serialize};
-use std::{
-    cell::RefCell,
-    collections::HashMap,
-    path::PathBuf,
-    time::{Duration, Instant},
+use anyhow::{Context, Result};
+use clap::{ArgAction, Parser, Subcommand};
+use ghaf_mem_manager::{
+    config::{
+        parse_size, Backend, Config, HostConfig, HostSettings, Policy, RecordFormat,
+        RecordSettings, ShutdownConfig, ShutdownSettings, VmConfig, VmSettings,
+    },
+    control::{self, ControlClient},
+    manager::{Manager, ManagerHandle, VmStatus},
+    metrics::{self, Endpoint},
+    recorder,
+    simulate::{self, Summary},
+    state,
 };
-use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufStream},
-    net::UnixStream,
-    sync::mpsc,
-};
-use tracing::{info, warn};
+use serde_json::{json, Value};
+use std::{io::Write, path::PathBuf, time::Duration};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tracing::{error, info, warn};
 
+// -h is taken by --high, so help only has the long flag
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    /// Path to QMP socket
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    disable_help_flag = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Manage the memory of the VMs, the default without a subcommand
+    #[command(disable_help_flag = true)]
+    Run(Box<RunArgs>),
+    /// Show the state of the managed VMs
+    Status(ClientArgs),
+    /// Print pressure and memory size of the VMs as they change
+    Watch {
+        #[command(flatten)]
+        client: ClientArgs,
+        /// Seconds between updates
+        #[arg(short, long, default_value_t = 1)]
+        interval: u64,
+    },
+    /// Pin a VM to a fixed memory size
+    Set {
+        #[command(flatten)]
+        client: ClientArgs,
+        vm: String,
+        /// Memory size with a K, M, G or T suffix, in MiB without one
+        #[arg(value_parser = parse_pin_size)]
+        size: usize,
+    },
+    /// Return a pinned VM to its policy
+    Unpin {
+        #[command(flatten)]
+        client: ClientArgs,
+        vm: String,
+    },
+    /// Stop managing a VM, its memory size is left as it is
+    Pause {
+        #[command(flatten)]
+        client: ClientArgs,
+        vm: String,
+    },
+    /// Resume managing a paused VM
+    Resume {
+        #[command(flatten)]
+        client: ClientArgs,
+        vm: String,
+    },
+    /// Replay a trace of guest memory demand against a policy, printing the
+    /// balloon size over time as CSV
+    #[command(disable_help_flag = true)]
+    Simulate(SimulateArgs),
+}
+
+#[derive(clap::Args)]
+struct ClientArgs {
+    /// Control socket of the running manager
+    #[arg(long, default_value = control::DEFAULT_SOCKET)]
+    control: PathBuf,
+}
+
+#[derive(clap::Args)]
+struct RunArgs {
+    /// Print help
+    #[arg(long, action = ArgAction::Help)]
+    help: Option<bool>,
+
+    /// Configuration file listing the VMs to manage
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Serve the control interface on this socket
+    #[arg(long)]
+    control: Option<PathBuf>,
+
+    /// Serve metrics on this socket path or loopback address:port
+    #[arg(long)]
+    metrics: Option<Endpoint>,
+
+    /// Keep the state of the VMs in this file to continue from it after a
+    /// restart
+    #[arg(long)]
+    state: Option<PathBuf>,
+
+    /// Record samples and decisions to this file
+    #[arg(long)]
+    record: Option<PathBuf>,
+
+    /// Format of the recording [default: json-lines]
+    #[arg(long, value_enum)]
+    record_format: Option<RecordFormat>,
+
+    /// Path to QMP socket of an additional VM
     #[arg(short, long)]
     socket: Vec<PathBuf>,
 
-    /// Monitoring interval in seconds
-    #[arg(short, long, default_value_t = 1)]
-    interval: u64,
+    #[command(flatten)]
+    policy: PolicyArgs,
+
+    /// Timeout for QMP commands in seconds [default: 5]
+    #[arg(long)]
+    qmp_timeout: Option<u64>,
+
+    /// Resize guests through the virtio-mem device with this id instead of
+    /// the balloon
+    #[arg(long)]
+    virtio_mem: Option<String>,
+
+    /// Compute and log decisions without changing the memory size of the
+    /// VMs
+    #[arg(long)]
+    dry_run: bool,
+
+    /// How often host memory is checked in seconds [default: 1]
+    #[arg(long)]
+    host_interval: Option<u64>,
+
+    /// Host memory kept free, VMs are not grown into it [default: 512M]
+    #[arg(long, value_parser = parse_size)]
+    host_reserve: Option<usize>,
 
-    /// Minimum ballooning interval
-    #[arg(short, long, default_value_t = 3)]
-    balloon_interval: u64,
+    /// Host memory below which VMs are shrunk right away [default: 256M]
+    #[arg(long, value_parser = parse_size)]
+    host_critical: Option<usize>,
+
+    /// Host memory pressure (PSI some avg10) above which VMs are not grown
+    /// [default: 20]
+    #[arg(long)]
+    host_pressure: Option<f32>,
+
+    /// Return every VM to its maximum size on SIGTERM or SIGINT
+    #[arg(long)]
+    restore_on_exit: bool,
+
+    /// Seconds to wait for the guests to confirm their size when exiting
+    /// [default: 10]
+    #[arg(long)]
+    shutdown_timeout: Option<u64>,
+}
+
+/// Settings that override the configuration file for all VMs.
+#[derive(clap::Args)]
+struct PolicyArgs {
+    /// Monitoring interval in seconds [default: 1]
+    #[arg(short, long)]
+    interval: Option<u64>,
+
+    /// Minimum ballooning interval [default: 3]
+    #[arg(short, long)]
+    balloon_interval: Option<u64>,
 
     /// Minimum memory size
-    #[arg(short, long, default_value_t = usize::MIN)]
-    minimum: usize,
+    #[arg(short, long, value_parser = parse_size)]
+    minimum: Option<usize>,
 
     /// Maximum memory size
-    #[arg(short = 'M', long, default_value_t = usize::MAX)]
-    maximum: usize,
+    #[arg(short = 'M', long, value_parser = parse_size)]
+    maximum: Option<usize>,
 
-    /// Low memory presure
-    #[arg(short, long, default_value_t = 70)]
-    low: u8,
+    /// Low memory presure [default: 70]
+    #[arg(short, long)]
+    low: Option<u8>,
 
-    /// High memory pressure
-    #[arg(short, long, default_value_t = 80)]
-    high: u8,
-}
+    /// High memory pressure [default: 80]
+    #[arg(short, long)]
+    high: Option<u8>,
+
+    /// Policy deciding the memory size [default: threshold]
+    #[arg(long, value_enum)]
+    policy: Option<Policy>,
 
-#[derive(Serialize, Debug)]
-#[serde(rename_all = "kebab-case")]
-struct QmpCommand {
-    execute: &'static str,
-    #[serde(skip_serializing_if = "HashMap::is_empty")]
-    arguments: HashMap<&'static str, serde_json::Value>,
+    /// Memory pressure the pid policy aims for [default: 75]
+    #[arg(long)]
+    target_pressure: Option<u8>,
 }
 
-impl QmpCommand {
-    pub fn new(cmd: &'static str) -> Self {
-        Self {
-            execute: cmd,
-            arguments: HashMap::new(),
+impl PolicyArgs {
+    fn overrides(&self) -> VmSettings {
+        VmSettings {
+            policy: self.policy,
+            target_pressure: self.target_pressure,
+            interval: self.interval,
+            balloon_interval: self.balloon_interval,
+            minimum: self.minimum,
+            maximum: self.maximum,
+            low: self.low,
+            high: self.high,
+            ..Default::default()
         }
     }
-
-    pub fn arg<T: Into<serde_json::Value>>(self, key: &'static str, v: T) -> Self {
-        let Self {
-            execute,
-            mut arguments,
-        } = self;
-        arguments.insert(key, v.into());
-        Self { execute, arguments }
-    }
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "kebab-case")]
-struct BalloonInfo {
-    actual: usize,
-}
+#[derive(clap::Args)]
+struct SimulateArgs {
+    /// Print help
+    #[arg(long, action = ArgAction::Help)]
+    help: Option<bool>,
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "kebab-case")]
-struct MemoryInfo {
-    base_memory: usize,
-    plugged_memory: usize,
-}
+    /// Recording, or CSV file with time and demand columns
+    trace: PathBuf,
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "kebab-case")]
-struct GuestMemoryStats {
-    stat_available_memory: usize,
-    stat_free_memory: usize,
-}
+    /// VM of the trace to replay
+    #[arg(long)]
+    vm: Option<String>,
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "kebab-case")]
-struct GuestMemoryInfo {
-    last_update: usize,
-    stats: GuestMemoryStats,
-}
+    /// Configuration file to take the settings of the VM from
+    #[arg(short, long)]
+    config: Option<PathBuf>,
 
-#[derive(Deserialize, Debug)]
-struct Empty {}
+    /// Memory of the guest [default: recorded total memory]
+    #[arg(long, value_parser = parse_size)]
+    memory: Option<usize>,
 
-type ReplyChannel = mpsc::Sender<serde_json::Value>;
-type CommandChannel = mpsc::Sender<(QmpCommand, ReplyChannel)>;
+    /// Balloon size at the start [default: all memory]
+    #[arg(long, value_parser = parse_size)]
+    size: Option<usize>,
 
-struct QmpConnection {
-    path: PathBuf,
-    channel: RefCell<Option<CommandChannel>>,
-    last_balloon: RefCell<Instant>,
+    #[command(flatten)]
+    policy: PolicyArgs,
 }
 
-impl QmpConnection {
-    fn new<P: Into<PathBuf>>(path: P) -> Self {
-        Self {
-            path: path.into(),
-            channel: RefCell::new(None),
-            last_balloon: RefCell::new(Instant::now()),
+/// Prints the steps of a simulation as CSV and a summary to stderr.
+fn simulate(args: SimulateArgs) -> Result<()> {
+    let trace = simulate::load_trace(&args.trace, args.vm.as_deref())?;
+    let config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let name = args
+        .vm
+        .clone()
+        .or_else(|| trace[0].vm.clone())
+        .unwrap_or_else(|| "trace".into());
+    let vm = config.resolve_vm(&name, &args.policy.overrides())?;
+    let memory = args
+        .memory
+        .or_else(|| trace.iter().filter_map(|sample| sample.memory).max())
+        .context("The trace does not record the guest memory, give it with --memory")?;
+    let steps = simulate::simulate(&trace, &vm, memory, args.size.unwrap_or(memory));
+    let print = || -> std::io::Result<()> {
+        let mut out = std::io::stdout().lock();
+        writeln!(out, "time,demand,size,pressure,operations")?;
+        for step in &steps {
+            writeln!(
+                out,
+                "{:.3},{},{},{},{}",
+                step.time, step.demand, step.size, step.pressure, step.operations
+            )?;
         }
+        Ok(())
+    };
+    match print() {
+        // Output cut short, e.g. by head
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => return Ok(()),
+        result => result?,
     }
+    eprintln!("Policy: {:?}", vm.policy);
+    eprintln!("{}", Summary::new(&steps, &vm));
+    Ok(())
+}
 
-    async fn connect(
-        &self,
-    ) -> Result<(
-        impl std::future::Future<Output = ()>,
-        mpsc::Receiver<serde_json::Value>,
-    )> {
-        let mut stream = BufStream::new(
-            UnixStream::connect(&self.path)
-                .await
-                .context("Failed to connect to QMP socket")?,
-        );
-        info!("Connected to {}", self.path.display());
-        let mut buf = vec![];
-        stream.read_until(b'\n', &mut buf).await?;
-        buf.clear();
-        stream
-            .write_all(&serde_json::to_vec(&QmpCommand::new("qmp_capabilities"))?)
-            .await?;
-        stream.write_all(b"\n").await?;
-        stream.flush().await?;
-        stream.read_until(b'\n', &mut buf).await?;
-
-        let (sender, mut receiver) = mpsc::channel(16);
-        let (evsender, evreceiver) = mpsc::channel(16);
-        *self.channel.borrow_mut() = Some(sender);
-        let mut tx: Option<ReplyChannel> = None;
-        let task = async move {
-            loop {
-                if let Some(curtx) = tx.take() {
-                    buf.clear();
-                    while let Ok(len) = stream.read_until(b'\n', &mut buf).await {
-                        if len == 0 {
-                            return;
-                        }
-                        let Ok(serde_json::Value::Object(mut data)) = serde_json::from_slice(&buf)
-                        else {
-                            continue;
-                        };
-                        if let Some(reply) = data.remove("return") {
-                            let _ = curtx.send(reply).await;
-                            break;
-                        } else if evsender
-                            .send(serde_json::Value::Object(data))
-                            .await
-                            .is_err()
-                        {
-                            return;
-                        }
-                        buf.clear();
-                    }
-                } else {
-                    buf.clear();
-                    tokio::select! {
-                        cmd = receiver.recv() => {
-                            let Some((cmd, newtx)) = cmd else { break; };
-                            if let Ok(vec) = serde_json::to_vec(&cmd) {
-                                if stream.write_all(&vec).await.is_err() ||
-                                    stream.write_all(b"\n").await.is_err() ||
-                                    stream.flush().await.is_err() {
-                                    return;
-                                }
-                                tx = Some(newtx);
-                            } else {
-                                warn!("Command serialization failed");
-                            }
-                        },
-                        Ok(len) = stream.read_until(b'\n', &mut buf) => {
-                            if len == 0 {
-                                return;
-                            }
-                            let Ok(data) = serde_json::from_slice(&buf) else { continue; };
-                            let serde_json::Value::Object(data) = data else { continue; };
-                            if evsender.send(serde_json::Value::Object(data)).await.is_err() {
-                                return;
-                            }
-                        },
-                    }
-                }
-            }
-        };
-
-        Ok((task, evreceiver))
-    }
-
-    pub async fn disconnect(&self) -> Result<()> {
-        self.channel.borrow_mut().take();
-        Ok(())
+impl RunArgs {
+    fn vm_overrides(&self) -> VmSettings {
+        VmSettings {
+            backend: self.virtio_mem.clone().map(Backend::VirtioMem),
+            qmp_timeout: self.qmp_timeout,
+            // Without the flag the configuration file decides
+            dry_run: self.dry_run.then_some(true),
+            ..self.policy.overrides()
+        }
     }
 
-    async fn send_command<T: for<'a> Deserialize<'a>>(&self, cmd: QmpCommand) -> Result<T> {
-        let (tx, mut rx) = mpsc::channel(1);
-        let Some(channel) = self.channel.borrow().as_ref().cloned() else {
-            bail!("Not connected");
-        };
-        channel.send((cmd, tx)).await?;
-        Ok(serde_json::from_value(
-            rx.recv().await.context("Invalid response")?,
-        )?)
+    fn host_overrides(&self) -> HostSettings {
+        HostSettings {
+            interval: self.host_interval,
+            reserve: self.host_reserve,
+            critical: self.host_critical,
+            pressure_limit: self.host_pressure,
+        }
     }
 
-    pub async fn query_balloon(&self) -> Result<BalloonInfo> {
-        let cmd = QmpCommand::new("query-balloon");
-        self.send_command(cmd).await
+    fn record_overrides(&self) -> RecordSettings {
+        RecordSettings {
+            path: self.record.clone(),
+            format: self.record_format,
+            ..Default::default()
+        }
     }
 
-    pub async fn balloon(&self, size: usize) -> Result<()> {
-        let cmd = QmpCommand::new("balloon").arg("value", size);
-        self.send_command::<Empty>(cmd)
-            .await
-            .map(|_| ())
-            .inspect(|_| *self.last_balloon.borrow_mut() = Instant::now())
+    fn shutdown_overrides(&self) -> ShutdownSettings {
+        ShutdownSettings {
+            restore: self.restore_on_exit.then_some(true),
+            timeout: self.shutdown_timeout,
+        }
     }
 
-    pub async fn query_memory(&self) -> Result<MemoryInfo> {
-        let cmd = QmpCommand::new("query-memory-size-summary");
-        self.send_command(cmd).await
+    /// Reads the configuration file and applies the command line to it.
+    fn resolve(&self) -> Result<(Vec<VmConfig>, HostConfig)> {
+        self.config()?
+            .resolve(&self.vm_overrides(), &self.host_overrides())
     }
 
-    pub async fn set_stats_interval(&self, ival: std::time::Duration) -> Result<()> {
-        let cmd = QmpCommand::new("qom-set")
-            .arg("path", "/machine/peripheral/balloon0")
-            .arg("property", "guest-stats-polling-interval")
-            .arg("value", ival.as_secs());
-        self.send_command::<Empty>(cmd).await.map(|_| ())
+    fn config(&self) -> Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        config
+            .vm
+            .extend(self.socket.iter().map(|socket| VmSettings {
+                socket: Some(socket.clone()),
+                ..Default::default()
+            }));
+        Ok(config)
     }
+}
 
-    pub async fn query_stats(&self) -> Result<GuestMemoryInfo> {
-        let cmd = QmpCommand::new("qom-get")
-            .arg("path", "/machine/peripheral/balloon0")
-            .arg("property", "guest-stats");
-        self.send_command(cmd).await
+/// Reloads the configuration on every SIGHUP.
+///
+/// An invalid configuration is reported and the current one kept.
+async fn reload_on_hangup(args: RunArgs, mut hangup: Signal, manager: ManagerHandle) -> Result<()> {
+    while hangup.recv().await.is_some() {
+        info!("Got SIGHUP, reloading configuration");
+        match args.resolve() {
+            Ok((vms, host)) => manager.reload(vms, host).await?,
+            Err(e) => warn!("Keeping the current configuration: {e:#}"),
+        }
     }
+    Ok(())
 }
 
-#[derive(Debug)]
-struct MemoryStats {
-    balloon_size: usize,
-    base_memory: usize,
-    plugged_memory: usize,
-    total_memory: usize,
-    free_memory: usize,
-    available_memory: usize,
+/// Shuts the manager down on the first SIGTERM or SIGINT.
+async fn shutdown_on_signal(
+    mut terminate: Signal,
+    mut interrupt: Signal,
+    config: ShutdownConfig,
+    manager: ManagerHandle,
+) -> Result<()> {
+    let name = tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = interrupt.recv() => "SIGINT",
+    };
+    info!("Got {name}, shutting down");
+    manager.shutdown(config).await
 }
 
-impl MemoryStats {
-    pub fn pressure(&self) -> u8 {
-        ((self.balloon_size - self.available_memory) as f64 * 100. / self.balloon_size as f64)
-            .round() as u8
+/// Runs the manager until it fails or is stopped by a signal.
+async fn run(args: RunArgs) -> Result<()> {
+    tracing_subscriber::fmt::init();
+    let config = args.config()?;
+    let (vms, host) = config.resolve(&args.vm_overrides(), &args.host_overrides())?;
+    let mut manager = Manager::new(vms, host);
+    if let Some(path) = args.state.clone().or(config.state.clone()) {
+        let saved = state::load(&path).unwrap_or_else(|e| {
+            warn!("Ignoring the saved state: {e:#}");
+            Default::default()
+        });
+        manager = manager.with_state_file(path, saved);
     }
-
-    pub fn reserved(&self) -> usize {
-        self.balloon_size - self.available_memory
+    // Like the sockets, recording is only set up at startup
+    if let Some(record) = args.record_overrides().or(&config.record).resolve()? {
+        manager = manager.with_recorder(recorder::spawn(record));
+    }
+    // The control socket and metrics endpoint are only set up at startup
+    if let Some(path) = args.control.clone().or(config.control) {
+        let listener = control::bind(&path)?;
+        let handle = manager.handle();
+        tokio::spawn(async move {
+            if let Err(e) = control::serve(listener, handle).await {
+                error!("Control socket failed: {e:#}");
+            }
+        });
     }
+    if let Some(endpoint) = args.metrics.clone().or(config.metrics) {
+        let listener = metrics::bind(&endpoint).await?;
+        let handle = manager.handle();
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(listener, handle).await {
+                error!("Metrics endpoint failed: {e:#}");
+            }
+        });
+    }
+    let shutdown = args.shutdown_overrides().or(&config.shutdown).resolve();
+    let terminate = signal(SignalKind::terminate())?;
+    let interrupt = signal(SignalKind::interrupt())?;
+    let handle = manager.handle();
+    tokio::spawn(async move {
+        if let Err(e) = shutdown_on_signal(terminate, interrupt, shutdown, handle).await {
+            error!("Shutdown failed: {e:#}");
+        }
+    });
+    let hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(reload_on_hangup(args, hangup, manager.handle()));
+    manager.run().await
 }
 
-impl std::fmt::Display for MemoryStats {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        write!(
-            f,
-            "Memory stats:\n\
-             Balloon size: {} MiB\n\
-             Base memory: {} MiB\n\
-             Plugged memory: {} MiB\n\
-             Total memory: {} MiB\n\
-             Free memory: {} MiB\n\
-             Available memory: {} MiB",
-            self.balloon_size / 1024 / 1024,
-            self.base_memory / 1024 / 1024,
-            self.plugged_memory / 1024 / 1024,
-            self.total_memory / 1024 / 1024,
-            self.free_memory / 1024 / 1024,
-            self.available_memory / 1024 / 1024
-        )
+/// Parses the size given to `set`, where a bare number is in MiB rather than
+/// bytes, so that `set gui-vm 4096` does not pin the VM to 4 KiB.
+fn parse_pin_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    if s.chars().all(|c| c.is_ascii_digit()) {
+        parse_size(&format!("{s}M"))
+    } else {
+        parse_size(s)
     }
 }
 
-async fn monitor_memory(args: Args) -> Result<()> {
-    let qmps: Vec<_> = args.socket.iter().map(QmpConnection::new).collect();
-    let dur = Duration::from_secs(args.interval);
-    let mut ival = tokio::time::interval(dur);
-    let mut last = None;
+fn mib(size: usize) -> String {
+    format!("{}M", size / 1024 / 1024)
+}
+
+fn print_status(vms: &[VmStatus]) {
+    let width = vms.iter().map(|vm| vm.name.len()).max().unwrap_or(0).max(4);
+    println!(
+        "{:width$} {:>8} {:>8} {:>8} {:>8} {:>9}  STATE",
+        "NAME", "SIZE", "WANTED", "TARGET", "PRESSURE", "AVAILABLE"
+    );
+    for vm in vms {
+        let state = match (vm.paused, vm.pinned) {
+            (true, _) => "paused".to_owned(),
+            (false, Some(size)) => format!("pinned to {}", mib(size)),
+            (false, None) => "managed".to_owned(),
+        };
+        let Some(report) = &vm.report else {
+            println!(
+                "{:width$} {:>8} {:>8} {:>8} {:>8} {:>9}  {state}, no stats yet",
+                vm.name, "-", "-", "-", "-", "-"
+            );
+            continue;
+        };
+        println!(
+            "{:width$} {:>8} {:>8} {:>8} {:>7}% {:>9}  {state}",
+            vm.name,
+            mib(report.size),
+            mib(report.wanted),
+            report.target.map_or("-".into(), mib),
+            report.pressure,
+            mib(report.stats.available_memory),
+        );
+    }
+}
 
+/// Prints a line whenever the pressure or size of a VM changes.
+async fn watch(client: &mut ControlClient, interval: Duration) -> Result<()> {
+    let mut last = std::collections::HashMap::new();
+    let mut ival = tokio::time::interval(interval);
     loop {
         ival.tick().await;
-        for qmp in &qmps {
-            let (task, mut receiver) = match qmp.connect().await {
-                Ok(a) => a,
-                Err(e) => {
-                    warn!(
-                        "Connection to {} failed: {e}, trying again later",
-                        qmp.path.display()
-                    );
-                    continue;
-                }
+        for vm in client.list().await? {
+            let Some(report) = vm.report else {
+                continue;
             };
-            tokio::select! {
-                e = async {
-                    qmp.set_stats_interval(dur).await?;
-                    let balloon = qmp.query_balloon().await?;
-                    let memory = qmp.query_memory().await?;
-                    let guest_stats = qmp.query_stats().await?;
-
-                    #[allow(clippy::nonminimal_bool)]
-                    if !last.is_some_and(|last| last == guest_stats.last_update) {
-                        last = Some(guest_stats.last_update);
-                        let stats = MemoryStats {
-                            balloon_size: balloon.actual,
-                            base_memory: memory.base_memory,
-                            plugged_memory: memory.plugged_memory,
-                            total_memory: memory.base_memory + memory.plugged_memory,
-                            free_memory: guest_stats.stats.stat_free_memory,
-                            available_memory: guest_stats.stats.stat_available_memory,
-                        };
-
-                        let pressure = stats.pressure();
-                        if let Some(target) = if pressure < args.low {
-                            if qmp.last_balloon.borrow().elapsed().as_secs() > args.balloon_interval {
-                                info!("Pressure below limit, inflating balloon");
-                                Some(stats.reserved() * 100 / args.low as usize)
-                            } else {
-                                info!("Pressure below limit, waiting for stabilisation");
-                                None
-                            }
-                        } else if pressure > args.high {
-                            if qmp.last_balloon.borrow().elapsed().as_secs() > args.balloon_interval {
-                                info!("Pressure above limit, deflating balloon");
-                                Some(stats.total_memory.min(stats.reserved() * 100 / (args.high as usize - 2)))
-                            } else {
-                                info!("Pressure above limit, waiting for stabilisation");
-                                None
-                            }
-                        } else {
-                            None
-                        } {
-                            let target = target.clamp(args.minimum, args.maximum);
-                            if target != stats.balloon_size {
-                                qmp.balloon(target).await?;
-                            }
-                        }
-                    }
-
-                    qmp.disconnect().await
-                } => e,
-                _ = task => Ok(()),
-                _ = async move {
-                    while let Some(e) = receiver.recv().await {
-                        info!("Got event: {e:?}");
-                    }
-                } => Ok(()),
-            }?;
+            let current = (report.pressure, report.size, report.target);
+            if last.insert(vm.name.clone(), current) == Some(current) {
+                continue;
+            }
+            println!(
+                "{} {}: pressure {}%, size {}{}",
+                time_of_day(),
+                vm.name,
+                report.pressure,
+                mib(report.size),
+                report
+                    .target
+                    .map_or(String::new(), |target| format!(", target {}", mib(target))),
+            );
         }
     }
 }
 
-#[tokio::main(flavor = "current_thread")]
+/// Current time of day in UTC.
+fn time_of_day() -> String {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    format!(
+        "{:02}:{:02}:{:02}",
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+async fn call(client: ClientArgs, method: &str, params: Value) -> Result<()> {
+    ControlClient::connect(&client.control)
+        .await?
+        .call::<Value>(method, params)
+        .await?;
+    Ok(())
+}
+
+#[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-    let args = Args::parse();
-    monitor_memory(args).await
+    let cli = Cli::parse();
+    match cli.command {
+        None => run(cli.run).await,
+        Some(Command::Run(args)) => run(*args).await,
+        Some(Command::Status(client)) => {
+            let vms = ControlClient::connect(&client.control)
+                .await?
+                .list()
+                .await?;
+            print_status(&vms);
+            Ok(())
+        }
+        Some(Command::Watch { client, interval }) => {
+            let mut client = ControlClient::connect(&client.control).await?;
+            watch(&mut client, Duration::from_secs(interval.max(1))).await
+        }
+        Some(Command::Set { client, vm, size }) => {
+            call(client, "pin", json!({"vm": vm, "size": size})).await
+        }
+        Some(Command::Unpin { client, vm }) => call(client, "unpin", json!({"vm": vm})).await,
+        Some(Command::Pause { client, vm }) => call(client, "pause", json!({"vm": vm})).await,
+        Some(Command::Resume { client, vm }) => call(client, "resume", json!({"vm": vm})).await,
+        Some(Command::Simulate(args)) => simulate(args),
+    }
 }
@@ -0,0 +1,1144 @@
+/*
+ * Copyright 2025 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Memory management loop.
+//!
+//! Every VM is handled by its own task with its own interval, so a slow or
+//! hung QMP socket only delays decisions for that VM. The tasks report their
+//! stats to a central [`Manager`], which has the view over all VMs and caps
+//! how far each of them may grow, so that together they stay within the
+//! memory the host can spare.
+
+use crate::{
+    backend::MemoryBackend,
+    config::{HostConfig, ShutdownConfig, VmConfig},
+    host::{self, Arbiter, HostMemory},
+    metrics::{Counters, VmCounters},
+    policy::{self, BalloonPolicy, Decision},
+    qmp::{
+        commands::{
+            query_guest_stats, set_guest_stats_interval, QueryBalloon, QueryMemorySizeSummary,
+        },
+        ConnectionState, Event, QmpConnection,
+    },
+    recorder::{self, Record},
+    state::{self, SavedState, VmState},
+    stats::MemoryStats,
+};
+use anyhow::{anyhow, bail, ensure, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{broadcast, mpsc, oneshot, watch},
+    task::JoinHandle,
+    time::{Interval, MissedTickBehavior},
+};
+use tracing::{debug, info, warn};
+
+/// Number of VM events buffered for each subscriber.
+const EVENT_QUEUE_LEN: usize = 64;
+
+/// Smallest size a VM is pinned or shrunk to, whatever its minimum.
+const MIN_VM_SIZE: usize = 256 * 1024 * 1024;
+
+/// How often the state file is written, besides after commands.
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Latest state of a VM, as sent by its task to the manager.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VmReport {
+    #[serde(skip)]
+    pub name: String,
+    pub stats: MemoryStats,
+    pub pressure: u8,
+    /// Current memory size of the guest
+    pub size: usize,
+    /// Size the policy wants the guest to have, before host limits
+    pub wanted: usize,
+    /// Size requested but not yet confirmed by the guest
+    pub target: Option<usize>,
+    /// State to keep across restarts
+    #[serde(skip)]
+    pub saved: SavedState,
+}
+
+/// State of a VM as shown to users of the manager.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VmStatus {
+    pub name: String,
+    /// Management suspended on request
+    pub paused: bool,
+    /// Size the VM is pinned to
+    pub pinned: Option<usize>,
+    pub counters: VmCounters,
+    /// Missing until the first stats of the VM arrived
+    #[serde(flatten)]
+    pub report: Option<VmReport>,
+}
+
+/// Requests for the task of a VM.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VmCommand {
+    /// Suspend management, the memory size is left as it is
+    Pause,
+    Resume,
+    /// Keep the VM at a fixed size, or return it to its policy with `None`
+    Pin(Option<usize>),
+    /// Decide and apply a new size now, without waiting for the balloon
+    /// interval
+    Evaluate,
+}
+
+/// Noteworthy changes in the state of a VM, for subscribers of the
+/// manager.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmEvent {
+    /// The guest stopped reporting stats, it is grown to a safe size
+    StatsLost { name: String },
+    /// The guest reports stats again
+    StatsRecovered { name: String },
+}
+
+/// State of host memory as seen by the manager.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HostState {
+    Sufficient,
+    /// VMs cannot grow as much as they want
+    Short,
+    /// Below the critical watermark, VMs are shrunk
+    Critical,
+}
+
+/// Limit set by the manager on a VM.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Limit {
+    /// Largest size the VM may grow to
+    Cap(usize),
+    /// Size the VM must shrink to right away
+    Reclaim(usize),
+}
+
+/// Manager side of a VM task.
+struct VmHandle {
+    /// Current settings, updates are picked up by the running task
+    config: watch::Sender<VmConfig>,
+    limit: watch::Sender<Limit>,
+    commands: mpsc::UnboundedSender<VmCommand>,
+    /// Asks the task to return the VM to its maximum size and exit
+    restore: watch::Sender<()>,
+    report: Option<VmReport>,
+    /// State of the previous run until the first report
+    saved: Option<SavedState>,
+    counters: Arc<Counters>,
+    /// Mirrors of the state set by commands
+    paused: bool,
+    pinned: Option<usize>,
+    /// Returns the state of the VM once restored
+    task: JoinHandle<Option<SavedState>>,
+}
+
+impl VmHandle {
+    /// State to write to the state file.
+    fn saved(&self) -> SavedState {
+        let saved = self.report.as_ref().map(|report| &report.saved);
+        SavedState {
+            user_paused: self.paused,
+            pinned: self.pinned,
+            ..saved.or(self.saved.as_ref()).cloned().unwrap_or_default()
+        }
+    }
+
+    fn status(&self, name: &str) -> VmStatus {
+        VmStatus {
+            name: name.to_owned(),
+            paused: self.paused,
+            pinned: self.pinned,
+            counters: self.counters.snapshot(),
+            report: self.report.clone(),
+        }
+    }
+}
+
+impl Drop for VmHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Requests to a running [`Manager`].
+enum Control {
+    Reload {
+        vms: Vec<VmConfig>,
+        host: HostConfig,
+    },
+    Status(oneshot::Sender<Vec<VmStatus>>),
+    Command {
+        vm: Option<String>,
+        command: VmCommand,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    Shutdown {
+        config: ShutdownConfig,
+        reply: oneshot::Sender<()>,
+    },
+}
+
+/// Handle for controlling a running [`Manager`].
+#[derive(Clone)]
+pub struct ManagerHandle {
+    control: mpsc::Sender<Control>,
+    events: broadcast::Sender<VmEvent>,
+}
+
+impl ManagerHandle {
+    /// Replaces the configuration of the manager.
+    ///
+    /// VMs are matched by name: new VMs are started, VMs no longer listed
+    /// are dropped and changed settings are applied to the running tasks,
+    /// keeping their QMP connections and state.
+    pub async fn reload(&self, vms: Vec<VmConfig>, host: HostConfig) -> Result<()> {
+        self.send(Control::Reload { vms, host }).await
+    }
+
+    /// Returns the state of all VMs, ordered by name.
+    pub async fn status(&self) -> Result<Vec<VmStatus>> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Control::Status(reply)).await?;
+        Ok(rx.await?)
+    }
+
+    /// Sends `command` to the VM named `vm`, or to all VMs.
+    pub async fn command(&self, vm: Option<String>, command: VmCommand) -> Result<()> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Control::Command { vm, command, reply }).await?;
+        rx.await?
+    }
+
+    /// Stops the manager, first returning all VMs to their maximum size if
+    /// `config` says so. Returns once the manager is done.
+    pub async fn shutdown(&self, config: ShutdownConfig) -> Result<()> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Control::Shutdown { config, reply }).await?;
+        Ok(rx.await?)
+    }
+
+    async fn send(&self, control: Control) -> Result<()> {
+        self.control
+            .send(control)
+            .await
+            .map_err(|_| anyhow!("Manager is not running"))
+    }
+
+    /// Subscribes to the events of all VMs.
+    pub fn subscribe(&self) -> broadcast::Receiver<VmEvent> {
+        self.events.subscribe()
+    }
+}
+
+/// Central coordinator of the per-VM tasks.
+pub struct Manager {
+    vms: Vec<VmConfig>,
+    host: HostConfig,
+    control: (mpsc::Sender<Control>, mpsc::Receiver<Control>),
+    events: broadcast::Sender<VmEvent>,
+    records: Option<mpsc::Sender<Record>>,
+    state_file: Option<PathBuf>,
+    /// Loaded from the state file
+    saved: BTreeMap<String, SavedState>,
+}
+
+impl Manager {
+    pub fn new(vms: Vec<VmConfig>, host: HostConfig) -> Self {
+        Self {
+            vms,
+            host,
+            control: mpsc::channel(4),
+            events: broadcast::channel(EVENT_QUEUE_LEN).0,
+            records: None,
+            state_file: None,
+            saved: BTreeMap::new(),
+        }
+    }
+
+    /// Sends a [`Record`] of every monitoring round to `records`.
+    pub fn with_recorder(mut self, records: mpsc::Sender<Record>) -> Self {
+        self.records = Some(records);
+        self
+    }
+
+    /// Keeps the state of the VMs in `path`, continuing from `saved`.
+    pub fn with_state_file(mut self, path: PathBuf, saved: BTreeMap<String, SavedState>) -> Self {
+        self.state_file = Some(path);
+        self.saved = saved;
+        self
+    }
+
+    pub fn handle(&self) -> ManagerHandle {
+        ManagerHandle {
+            control: self.control.0.clone(),
+            events: self.events.clone(),
+        }
+    }
+
+    /// Starts one task per VM, collects their reports and arbitrates host
+    /// memory between them until shut down.
+    pub async fn run(self) -> Result<()> {
+        let (_, mut control) = self.control;
+        let (reports, mut receiver) = mpsc::channel(16);
+        let tasks = TaskChannels {
+            reports,
+            events: self.events,
+            records: self.records,
+        };
+        let mut handles = HashMap::new();
+        let mut saved = self.saved;
+        for config in self.vms {
+            let saved = saved.remove(&config.name);
+            Self::start(&mut handles, config, &tasks, saved);
+        }
+        let state_file = self.state_file;
+
+        let mut host = self.host;
+        let mut arbiter = Arbiter::new(host.clone());
+        let mut state = HostState::Sufficient;
+        let mut ival = interval(host.interval);
+        let mut save_ival = interval(SAVE_INTERVAL);
+        let (config, reply) = loop {
+            tokio::select! {
+                Some(report) = receiver.recv() => {
+                    if let Some(handle) = handles.get_mut(&report.name) {
+                        handle.report = Some(report);
+                    }
+                },
+                _ = ival.tick() => match HostMemory::read().await {
+                    Ok(host) => state = Self::arbitrate(&arbiter, &host, &handles, state),
+                    Err(e) => warn!("Failed to read host memory state: {e}"),
+                },
+                _ = save_ival.tick(), if state_file.is_some() => {
+                    Self::save(state_file.as_deref(), &handles).await;
+                },
+                Some(control) = control.recv() => match control {
+                    Control::Reload { vms, host: new_host } => {
+                        Self::reload(&mut handles, vms, &tasks);
+                        let changes = host.changes(&new_host);
+                        if !changes.is_empty() {
+                            info!("Host settings changed: {}", changes.join(", "));
+                            if new_host.interval != host.interval {
+                                ival = interval(new_host.interval);
+                            }
+                            arbiter = Arbiter::new(new_host.clone());
+                            host = new_host;
+                        }
+                    }
+                    Control::Status(reply) => {
+                        let mut status: Vec<_> = handles
+                            .iter()
+                            .map(|(name, handle)| handle.status(name))
+                            .collect();
+                        status.sort_by(|a, b| a.name.cmp(&b.name));
+                        let _ = reply.send(status);
+                    }
+                    Control::Command { vm, command, reply } => {
+                        let _ = reply.send(Self::command(&mut handles, vm, command));
+                        Self::save(state_file.as_deref(), &handles).await;
+                    }
+                    Control::Shutdown { config, reply } => break (config, reply),
+                },
+            }
+        };
+
+        // Tasks still sending reports get an error instead of waiting
+        drop(receiver);
+        if config.restore {
+            Self::restore(&mut handles, config.timeout).await;
+        }
+        // Saved after restoring, so that the next run finds the restored sizes
+        Self::save(state_file.as_deref(), &handles).await;
+        // Stops the remaining tasks
+        drop(handles);
+        let _ = reply.send(());
+        Ok(())
+    }
+
+    /// Has every VM returned to its maximum size and waits up to `timeout`
+    /// for the guests to confirm.
+    async fn restore(handles: &mut HashMap<String, VmHandle>, timeout: Duration) {
+        info!("Restoring the VMs to their maximum size");
+        for handle in handles.values() {
+            handle.restore.send_replace(());
+        }
+        let deadline = tokio::time::Instant::now() + timeout;
+        for (name, handle) in handles.iter_mut() {
+            match tokio::time::timeout_at(deadline, &mut handle.task).await {
+                Ok(Ok(Some(saved))) => match &mut handle.report {
+                    Some(report) => report.saved = saved,
+                    None => handle.saved = Some(saved),
+                },
+                Ok(_) => {}
+                Err(_) => warn!(
+                    "{name}: size not confirmed within {}s, giving up",
+                    timeout.as_secs()
+                ),
+            }
+        }
+    }
+
+    /// Writes the state of all VMs to `path`, if given.
+    async fn save(path: Option<&Path>, handles: &HashMap<String, VmHandle>) {
+        let Some(path) = path else {
+            return;
+        };
+        let vms = handles
+            .iter()
+            .map(|(name, handle)| (name.clone(), handle.saved()))
+            .collect();
+        if let Err(e) = state::save(path, vms).await {
+            warn!("Failed to save state: {e:#}");
+        }
+    }
+
+    /// Passes `command` to the task of `vm`, or of all VMs.
+    fn command(
+        handles: &mut HashMap<String, VmHandle>,
+        vm: Option<String>,
+        command: VmCommand,
+    ) -> Result<()> {
+        if let Some(vm) = &vm {
+            ensure!(handles.contains_key(vm), "Unknown VM {vm}");
+        }
+        let selected = |name: &String| vm.as_ref().is_none_or(|vm| vm == name);
+        if let VmCommand::Pin(Some(size)) = command {
+            for (name, handle) in handles.iter().filter(|(name, _)| selected(name)) {
+                let config = handle.config.borrow();
+                let minimum = config.minimum.max(MIN_VM_SIZE);
+                ensure!(
+                    size >= minimum,
+                    "{name} cannot be pinned below {} MiB",
+                    minimum / 1024 / 1024
+                );
+                ensure!(
+                    size <= config.maximum,
+                    "{name} cannot be pinned above its maximum of {} MiB",
+                    config.maximum / 1024 / 1024
+                );
+            }
+        }
+        for (_, handle) in handles.iter_mut().filter(|(name, _)| selected(name)) {
+            match command {
+                VmCommand::Pause => handle.paused = true,
+                VmCommand::Resume => handle.paused = false,
+                VmCommand::Pin(size) => handle.pinned = size,
+                VmCommand::Evaluate => {}
+            }
+            // The task only goes away together with its handle
+            let _ = handle.commands.send(command);
+        }
+        Ok(())
+    }
+
+    /// Starts the task of a VM.
+    fn start(
+        handles: &mut HashMap<String, VmHandle>,
+        config: VmConfig,
+        channels: &TaskChannels,
+        saved: Option<SavedState>,
+    ) {
+        let name = config.name.clone();
+        let (config, config_rx) = watch::channel(config);
+        // Not grown until the manager has seen the VM and given it a share
+        let (limit, limit_rx) = watch::channel(Limit::Cap(0));
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+        let (restore, restore_rx) = watch::channel(());
+        let counters = Arc::new(Counters::default());
+        let task = tokio::spawn(
+            VmTask::new(
+                config_rx,
+                channels.clone(),
+                limit_rx,
+                commands_rx,
+                restore_rx,
+                counters.clone(),
+                saved.clone(),
+            )
+            .run(),
+        );
+        handles.insert(
+            name,
+            VmHandle {
+                config,
+                limit,
+                commands,
+                restore,
+                report: None,
+                paused: saved.as_ref().is_some_and(|saved| saved.user_paused),
+                pinned: saved.as_ref().and_then(|saved| saved.pinned),
+                saved,
+                counters,
+                task,
+            },
+        );
+    }
+
+    /// Applies a new list of VMs, logging what changed.
+    fn reload(
+        handles: &mut HashMap<String, VmHandle>,
+        vms: Vec<VmConfig>,
+        channels: &TaskChannels,
+    ) {
+        let mut old = std::mem::take(handles);
+        for config in vms {
+            let name = config.name.clone();
+            let Some(handle) = old.remove(&name) else {
+                info!("{name}: added to the configuration");
+                Self::start(handles, config, channels, None);
+                continue;
+            };
+            let changes = handle.config.borrow().changes(&config);
+            if changes.is_empty() {
+                handles.insert(name, handle);
+                continue;
+            }
+            info!("{name}: settings changed: {}", changes.join(", "));
+            if handle.config.borrow().socket != config.socket {
+                // A different VM as far as QMP is concerned, start over
+                drop(handle);
+                Self::start(handles, config, channels, None);
+            } else {
+                handle.config.send_replace(config);
+                handles.insert(name, handle);
+            }
+        }
+        // Dropping the handles stops the tasks
+        for name in old.into_keys() {
+            info!("{name}: removed from the configuration, no longer managed");
+        }
+    }
+
+    /// Caps the growth of the VMs so that together they stay within the host
+    /// budget, or shrinks them right away when host memory is critical.
+    fn arbitrate(
+        arbiter: &Arbiter,
+        host: &HostMemory,
+        handles: &HashMap<String, VmHandle>,
+        was: HostState,
+    ) -> HostState {
+        let vms: Vec<_> = handles
+            .iter()
+            .filter_map(|(name, handle)| Some((name, handle, handle.report.as_ref()?)))
+            .collect();
+
+        // Stay in emergency mode until the reserve is restored
+        if arbiter.critical(host) || (was == HostState::Critical && arbiter.deficit(host) > 0) {
+            if was != HostState::Critical {
+                warn!(
+                    "Host memory critical, {} MiB available, reclaiming from low priority VMs",
+                    host.available / 1024 / 1024
+                );
+            }
+            let sizes: Vec<_> = vms
+                .iter()
+                .map(|(_, handle, report)| {
+                    let config = handle.config.borrow();
+                    (report.size, reclaim_floor(&config, report), config.priority)
+                })
+                .collect();
+            let targets = host::reclaim(arbiter.deficit(host), &sizes);
+            for ((name, handle, report), target) in vms.into_iter().zip(targets) {
+                let limit = if target < report.size {
+                    Limit::Reclaim(target)
+                } else {
+                    Limit::Cap(report.size)
+                };
+                Self::set_limit(name, handle, limit);
+            }
+            return HostState::Critical;
+        }
+        if was == HostState::Critical {
+            info!("Host memory recovered, leaving emergency mode");
+        }
+
+        let budget = arbiter.budget(host);
+        let demands: Vec<_> = vms
+            .iter()
+            .map(|(_, handle, report)| {
+                (
+                    report.wanted.saturating_sub(report.size),
+                    handle.config.borrow().priority,
+                )
+            })
+            .collect();
+        let short = demands.iter().map(|(demand, _)| demand).sum::<usize>() > budget;
+        let state = if short {
+            HostState::Short
+        } else {
+            HostState::Sufficient
+        };
+        if state != was {
+            if short {
+                info!(
+                    "Host memory short, {} MiB left for VMs, sharing by priority",
+                    budget / 1024 / 1024
+                );
+            } else {
+                info!("Host memory sufficient again");
+            }
+        }
+
+        // Capped even when there is enough, as VMs growing within the same
+        // interval could together take more than the budget
+        let grants = host::shares(budget, &demands);
+        for ((name, handle, report), grant) in vms.into_iter().zip(grants) {
+            Self::set_limit(name, handle, Limit::Cap(report.size + grant));
+        }
+        state
+    }
+
+    fn set_limit(name: &str, handle: &VmHandle, limit: Limit) {
+        handle.limit.send_if_modified(|old| {
+            if *old == limit {
+                return false;
+            }
+            match limit {
+                Limit::Cap(size) => debug!("{name}: limited to {} MiB", size / 1024 / 1024),
+                Limit::Reclaim(size) => debug!("{name}: reclaiming to {} MiB", size / 1024 / 1024),
+            }
+            *old = limit;
+            true
+        });
+    }
+}
+
+/// Size a VM may be shrunk to when host memory is critical: its minimum,
+/// but never below what the guest is using, so that it is not pushed into
+/// running out of memory.
+fn reclaim_floor(config: &VmConfig, report: &VmReport) -> usize {
+    config.minimum.max(MIN_VM_SIZE).max(report.stats.reserved())
+}
+
+fn interval(period: Duration) -> Interval {
+    let mut ival = tokio::time::interval(period);
+    ival.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ival
+}
+
+/// Requests `target` from the backend and returns the size requested. In
+/// dry-run mode the change is only logged and `None` returned.
+async fn resize(
+    config: &VmConfig,
+    backend: &mut MemoryBackend,
+    qmp: &QmpConnection,
+    stats: &MemoryStats,
+    size: usize,
+    target: usize,
+) -> Result<Option<usize>> {
+    if config.dry_run {
+        let action = if target < size { "inflate" } else { "deflate" };
+        info!(
+            "{}: would {action} to {} MiB",
+            config.name,
+            target / 1024 / 1024
+        );
+        return Ok(None);
+    }
+    Ok(Some(backend.resize(qmp, stats, target).await?))
+}
+
+/// Channels from the VM tasks to the manager and its subscribers.
+#[derive(Clone)]
+struct TaskChannels {
+    reports: mpsc::Sender<VmReport>,
+    events: broadcast::Sender<VmEvent>,
+    records: Option<mpsc::Sender<Record>>,
+}
+
+/// Management task of a single VM.
+struct VmTask {
+    config: VmConfig,
+    /// New settings, set by the manager on reload
+    updates: watch::Receiver<VmConfig>,
+    qmp: QmpConnection,
+    events: broadcast::Receiver<Event>,
+    /// Set up once connected
+    backend: Option<MemoryBackend>,
+    state: VmState,
+    policy: Box<dyn BalloonPolicy>,
+    channels: TaskChannels,
+    /// Set by the manager
+    limit: watch::Receiver<Limit>,
+    commands: mpsc::UnboundedReceiver<VmCommand>,
+    /// Changed by the manager when shutting down
+    restore: watch::Receiver<()>,
+    counters: Arc<Counters>,
+    /// State of the previous run, checked against the VM once connected
+    saved: Option<SavedState>,
+    /// Whether a connection was ever established, to tell reconnects apart
+    connected: bool,
+}
+
+impl VmTask {
+    fn new(
+        mut updates: watch::Receiver<VmConfig>,
+        channels: TaskChannels,
+        limit: watch::Receiver<Limit>,
+        commands: mpsc::UnboundedReceiver<VmCommand>,
+        restore: watch::Receiver<()>,
+        counters: Arc<Counters>,
+        saved: Option<SavedState>,
+    ) -> Self {
+        let config = updates.borrow_and_update().clone();
+        let mut state = VmState::default();
+        if let Some(saved) = &saved {
+            state.restore_requests(saved);
+        }
+        if config.dry_run {
+            info!(
+                "{}: dry run, memory size changes are only logged",
+                config.name
+            );
+        }
+        let qmp = QmpConnection::new(&config.socket).with_timeout(config.qmp_timeout);
+        Self {
+            events: qmp.subscribe(),
+            qmp,
+            policy: policy::from_config(&config),
+            config,
+            updates,
+            backend: None,
+            state,
+            channels,
+            limit,
+            commands,
+            restore,
+            counters,
+            saved,
+            connected: false,
+        }
+    }
+
+    /// Runs until the manager goes away, or until asked to restore the VM.
+    /// Then returns the state of the restored VM.
+    async fn run(mut self) -> Option<SavedState> {
+        let mut ival = interval(self.config.interval);
+        loop {
+            tokio::select! {
+                _ = ival.tick() => self.tick(false).await,
+                event = self.events.recv() => match event {
+                    Ok(event) => self.handle_event(event),
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("{}: missed {n} events", self.config.name);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                Ok(()) = self.updates.changed() => {
+                    let config = self.updates.borrow_and_update().clone();
+                    self.reconfigure(config, &mut ival);
+                },
+                Ok(()) = self.limit.changed() => {
+                    // Host memory is critical, do not wait for the next tick
+                    if matches!(*self.limit.borrow_and_update(), Limit::Reclaim(_)) {
+                        self.tick(false).await;
+                    }
+                },
+                Some(command) = self.commands.recv() => self.handle_command(command).await,
+                Ok(()) = self.restore.changed() => {
+                    return match self.restore_size().await {
+                        Ok(size) => Some(SavedState {
+                            target: None,
+                            ..self.state.save(size)
+                        }),
+                        Err(e) => {
+                            warn!("Restoring {} failed: {e}", self.config.name);
+                            None
+                        }
+                    };
+                },
+            }
+        }
+        None
+    }
+
+    /// Returns the VM to its maximum size, regardless of pins and pauses,
+    /// and waits for the guest to confirm it. Returns the size of the VM.
+    async fn restore_size(&mut self) -> Result<usize> {
+        match self.qmp.ensure_connected().await {
+            ConnectionState::Connected => {}
+            ConnectionState::Reconnected => self.backend = None,
+            ConnectionState::Down => bail!("QMP socket unavailable"),
+        }
+        let backend = match &mut self.backend {
+            Some(backend) => backend,
+            None => self
+                .backend
+                .insert(MemoryBackend::connect(&self.qmp, &self.config.backend).await?),
+        };
+        let qmp = &self.qmp;
+        let (balloon, memory, guest_stats) = tokio::try_join!(
+            qmp.execute(QueryBalloon),
+            qmp.execute(QueryMemorySizeSummary),
+            qmp.execute(query_guest_stats()),
+        )?;
+        let config = &self.config;
+        let stats = MemoryStats::new(&balloon, &memory, &guest_stats);
+        let size = backend.current_size(&stats);
+        let target = backend.max_size(&stats).min(config.maximum);
+        if size >= target {
+            return Ok(size);
+        }
+        info!(
+            "{}: restoring to {} MiB before exiting",
+            config.name,
+            target / 1024 / 1024
+        );
+        let Some(target) = resize(config, backend, qmp, &stats, size, target).await? else {
+            return Ok(size);
+        };
+        self.counters.resized(size, target);
+        loop {
+            match self.events.recv().await {
+                Ok(event) if backend.target_reached(&event, target) => break,
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => bail!("QMP connection closed"),
+            }
+        }
+        info!("{}: restored to {} MiB", config.name, target / 1024 / 1024);
+        Ok(target)
+    }
+
+    /// Switches to new settings on the existing connection.
+    fn reconfigure(&mut self, config: VmConfig, ival: &mut Interval) {
+        let interval_changed = config.interval != self.config.interval;
+        if interval_changed {
+            *ival = interval(config.interval);
+        }
+        if interval_changed || config.backend != self.config.backend {
+            // Set up again on the next tick, which also updates the guest
+            // stats polling interval
+            self.backend = None;
+            self.state.target = None;
+        }
+        self.qmp.set_timeout(config.qmp_timeout);
+        // Keep what the policy learned unless its settings changed
+        if self.config.policy_changed(&config) {
+            self.policy = policy::from_config(&config);
+        }
+        self.config = config;
+    }
+
+    /// Samples the VM and applies the decision of its policy. With `force`
+    /// the decision is made even without a new sample, on the last one, and
+    /// applied without waiting for the balloon interval.
+    async fn tick(&mut self, force: bool) {
+        if let Err(e) = self.try_tick(force).await {
+            self.counters.qmp_error();
+            warn!("Monitoring {} failed: {e}", self.config.name);
+        }
+    }
+
+    async fn try_tick(&mut self, force: bool) -> Result<()> {
+        let reconnected = match self.qmp.ensure_connected().await {
+            ConnectionState::Connected => false,
+            ConnectionState::Reconnected => true,
+            ConnectionState::Down => return Ok(()),
+        };
+        if reconnected {
+            if self.connected {
+                self.counters.reconnected();
+            }
+            self.connected = true;
+        }
+        if reconnected || self.backend.is_none() {
+            self.backend = None;
+            self.qmp
+                .execute(set_guest_stats_interval(self.config.interval.as_secs()))
+                .await?;
+            self.backend = Some(MemoryBackend::connect(&self.qmp, &self.config.backend).await?);
+        }
+        let Some(backend) = &mut self.backend else {
+            return Ok(());
+        };
+        if self.state.paused || self.state.user_paused {
+            return Ok(());
+        }
+
+        let qmp = &self.qmp;
+        let (balloon, memory, guest_stats) = tokio::try_join!(
+            qmp.execute(QueryBalloon),
+            qmp.execute(QueryMemorySizeSummary),
+            qmp.execute(query_guest_stats()),
+        )?;
+        let fresh = self.state.update_stats(guest_stats.last_update);
+        let config = &self.config;
+        let limit = *self.limit.borrow();
+        // Between two samples of the guest the stats are not new, but not
+        // lost either
+        let stale = !fresh && self.state.last_sample.elapsed() > config.stats_timeout;
+        if !fresh && !stale && !force && !matches!(limit, Limit::Reclaim(_)) {
+            return Ok(());
+        }
+        let stats = MemoryStats::new(&balloon, &memory, &guest_stats);
+        let pressure = stats.pressure();
+        let size = backend.current_size(&stats);
+        if let Some(saved) = self.saved.take() {
+            if self.state.resume(saved, size) {
+                info!(
+                    "{}: continuing with {} samples from the saved state",
+                    config.name,
+                    self.state.history.len()
+                );
+            } else {
+                info!(
+                    "{}: resized since the state was saved, starting afresh",
+                    config.name
+                );
+            }
+        }
+        let can_balloon = force || self.state.last_balloon.elapsed() > config.balloon_interval;
+        let notify = |event| {
+            // No subscribers is fine
+            let _ = self.channels.events.send(event);
+        };
+        if fresh {
+            debug!("{}: {stats}", config.name);
+            self.state.record(stats.clone());
+            if self.state.stats_lost {
+                info!("{}: guest stats are back", config.name);
+                self.state.stats_lost = false;
+                notify(VmEvent::StatsRecovered {
+                    name: config.name.clone(),
+                });
+            }
+        } else if stale && !self.state.stats_lost {
+            warn!(
+                "{}: no guest stats for {}s, growing to a safe size",
+                config.name,
+                self.state.last_sample.elapsed().as_secs()
+            );
+            self.state.stats_lost = true;
+            notify(VmEvent::StatsLost {
+                name: config.name.clone(),
+            });
+        }
+
+        // The wanted size is reported even while waiting, so the manager
+        // knows about the demand before memory is actually taken
+        let (wanted, mut reason) = if let Some(pinned) = self.state.pinned {
+            // Also checked when pinned, but the bounds may have been
+            // reloaded since
+            let wanted = pinned
+                .clamp(config.minimum, config.maximum)
+                .min(backend.max_size(&stats));
+            (wanted, Some("pinned".into()))
+        } else if self.state.stats_lost {
+            // Nothing is known about the guest, grow it step by step up to
+            // its maximum so that it does not run out of memory
+            let wanted = backend
+                .max_size(&stats)
+                .min(config.maximum)
+                .min(size + config.max_step)
+                .max(size);
+            (wanted, Some("no guest stats".into()))
+        } else {
+            match self.policy.decide(&self.state.history, size) {
+                Some(Decision { target, reason }) => {
+                    if !can_balloon {
+                        info!("{}: {reason}, waiting for stabilisation", config.name);
+                    } else if config.dry_run {
+                        // Logged with the size that would be requested
+                    } else if target < size {
+                        info!("{}: {reason}, inflating balloon", config.name);
+                    } else {
+                        info!("{}: {reason}, deflating balloon", config.name);
+                    }
+                    let wanted = target
+                        .min(backend.max_size(&stats))
+                        .clamp(config.minimum, config.maximum);
+                    (wanted, Some(reason))
+                }
+                None => (size, None),
+            }
+        };
+        let mut requested = None;
+
+        if let Limit::Reclaim(target) = limit {
+            // Emergency, the balloon interval does not apply
+            if target < size && self.state.target.is_none_or(|t| t > target) {
+                info!(
+                    "{}: host memory critical, shrinking to {} MiB",
+                    config.name,
+                    target / 1024 / 1024
+                );
+                requested = resize(config, backend, &self.qmp, &stats, size, target).await?;
+                if let Some(requested) = requested {
+                    self.counters.resized(size, requested);
+                }
+                self.state.last_balloon = Instant::now();
+                self.state.target = requested;
+                reason = Some("host memory critical".into());
+            }
+        } else if can_balloon && wanted != size {
+            let target = match limit {
+                Limit::Cap(cap) if wanted > size => wanted.min(cap.max(size)),
+                _ => wanted,
+            };
+            if target < wanted {
+                info!(
+                    "{}: host memory short, growing to {} MiB only",
+                    config.name,
+                    target / 1024 / 1024
+                );
+                reason = reason.map(|reason| reason + ", host memory short");
+            }
+            if target != size {
+                requested = resize(config, backend, &self.qmp, &stats, size, target).await?;
+                if let Some(requested) = requested {
+                    self.counters.resized(size, requested);
+                }
+                self.state.last_balloon = Instant::now();
+                self.state.target = requested;
+            }
+        }
+
+        if let Some(records) = &self.channels.records {
+            let record = Record {
+                timestamp: recorder::timestamp(),
+                vm: config.name.clone(),
+                stats: stats.clone(),
+                pressure,
+                size,
+                wanted,
+                target: requested,
+                reason,
+            };
+            if records.try_send(record).is_err() {
+                debug!("{}: recording queue full, dropping a record", config.name);
+            }
+        }
+
+        // The manager going away is handled by the task being dropped
+        let _ = self
+            .channels
+            .reports
+            .send(VmReport {
+                name: self.config.name.clone(),
+                stats,
+                pressure,
+                size,
+                wanted,
+                target: self.state.target,
+                saved: self.state.save(size),
+            })
+            .await;
+        Ok(())
+    }
+
+    async fn handle_command(&mut self, command: VmCommand) {
+        let name = &self.config.name;
+        match command {
+            VmCommand::Pause => {
+                info!("{name}: management paused on request");
+                self.state.user_paused = true;
+            }
+            VmCommand::Resume => {
+                info!("{name}: management resumed on request");
+                self.state.user_paused = false;
+            }
+            VmCommand::Pin(Some(size)) => {
+                info!("{name}: pinned to {} MiB", size / 1024 / 1024);
+                self.state.pinned = Some(size);
+                self.tick(true).await;
+            }
+            VmCommand::Pin(None) => {
+                info!("{name}: unpinned, back to its policy");
+                self.state.pinned = None;
+            }
+            VmCommand::Evaluate => self.tick(true).await,
+        }
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        let name = &self.config.name;
+        let state = &mut self.state;
+        if let (Some(backend), Some(target)) = (&self.backend, state.target) {
+            if backend.target_reached(&event, target) {
+                info!("{name}: target {} MiB reached", target / 1024 / 1024);
+                state.target = None;
+            }
+        }
+        match event {
+            Event::BalloonChange { actual } => {
+                debug!("{name}: balloon size {} MiB", actual / 1024 / 1024);
+            }
+            Event::Stop => {
+                info!("{name}: VM paused, suspending management");
+                state.paused = true;
+            }
+            Event::Resume => {
+                info!("{name}: VM resumed, resuming management");
+                state.paused = false;
+                // The guest could not report stats while it was paused
+                state.last_sample = Instant::now();
+            }
+            Event::GuestPanicked { action } => {
+                warn!("{name}: guest panicked ({action}), suspending management");
+                state.paused = true;
+            }
+            Event::Shutdown { reason, .. } | Event::Reset { reason, .. } => {
+                info!("{name}: guest restarted ({reason}), resetting state");
+                // This also gives the guest time to boot before ballooning
+                state.reset();
+            }
+            Event::MemoryDeviceSizeChange { qom_path, size, .. } => {
+                debug!("{name}: {qom_path} resized to {} MiB", size / 1024 / 1024);
+            }
+            Event::Other { name: event, .. } => debug!("{name}: got event {event}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, VmSettings};
+
+    const GIB: usize = 1024 * 1024 * 1024;
+
+    #[test]
+    fn reclaim_keeps_guests_alive() {
+        let config = Config::default()
+            .resolve_vm("vm", &VmSettings::default())
+            .unwrap();
+        assert_eq!(config.minimum, 0);
+        let report = |size, available| VmReport {
+            name: "vm".into(),
+            stats: MemoryStats {
+                balloon_size: size,
+                available_memory: available,
+                ..Default::default()
+            },
+            pressure: 0,
+            size,
+            wanted: size,
+            target: None,
+            saved: SavedState::default(),
+        };
+        // Busy, only its free memory is taken
+        let busy = report(4 * GIB, GIB);
+        assert_eq!(reclaim_floor(&config, &busy), 3 * GIB);
+        // Idle, shrunk to the smallest size at most
+        let idle = report(4 * GIB, 4 * GIB);
+        assert_eq!(reclaim_floor(&config, &idle), MIN_VM_SIZE);
+        let floors = [
+            (busy.size, reclaim_floor(&config, &busy), 1),
+            (idle.size, reclaim_floor(&config, &idle), 1),
+        ];
+        // However large the deficit
+        assert_eq!(host::reclaim(usize::MAX, &floors), [3 * GIB, MIN_VM_SIZE]);
+    }
+}
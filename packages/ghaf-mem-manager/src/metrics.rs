@@ -0,0 +1,417 @@
+/*
+ * Copyright 2025 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Prometheus metrics of the managed VMs.
+//!
+//! A GET of `/metrics` returns the gauges and counters of every VM in the
+//! OpenMetrics text format. The endpoint is plain HTTP, so it is only served
+//! on a Unix socket or a loopback address.
+
+use crate::{
+    control,
+    manager::{ManagerHandle, VmReport, VmStatus},
+};
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Write as _,
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, UnixListener},
+};
+use tracing::{debug, info};
+
+const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+/// Longest request head accepted from a client.
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// Where the metrics are served.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Endpoint {
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+impl FromStr for Endpoint {
+    type Err = anyhow::Error;
+
+    /// Parses an absolute socket path or a loopback `address:port`.
+    fn from_str(s: &str) -> Result<Self> {
+        if s.starts_with('/') {
+            return Ok(Self::Unix(s.into()));
+        }
+        let addr: SocketAddr = s
+            .parse()
+            .with_context(|| format!("{s} is neither a socket path nor an address:port"))?;
+        ensure!(
+            addr.ip().is_loopback(),
+            "metrics are only served on loopback addresses, not {}",
+            addr.ip()
+        );
+        Ok(Self::Tcp(addr))
+    }
+}
+
+impl TryFrom<String> for Endpoint {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+/// Counters of a VM task, shared with the manager.
+#[derive(Debug, Default)]
+pub struct Counters {
+    inflations: AtomicU64,
+    deflations: AtomicU64,
+    qmp_errors: AtomicU64,
+    reconnects: AtomicU64,
+}
+
+impl Counters {
+    /// Counts a resize from `size` to `target`.
+    pub fn resized(&self, size: usize, target: usize) {
+        let counter = if target < size {
+            &self.inflations
+        } else {
+            &self.deflations
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn qmp_error(&self) {
+        self.qmp_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reconnected(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> VmCounters {
+        VmCounters {
+            inflations: self.inflations.load(Ordering::Relaxed),
+            deflations: self.deflations.load(Ordering::Relaxed),
+            qmp_errors: self.qmp_errors.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Totals since the task of a VM was started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct VmCounters {
+    /// Balloon inflations, i.e. requests to shrink the guest
+    pub inflations: u64,
+    /// Balloon deflations, i.e. requests to grow the guest
+    pub deflations: u64,
+    /// Failed monitoring rounds
+    pub qmp_errors: u64,
+    /// QMP connections established after the first one
+    pub reconnects: u64,
+}
+
+/// Quotes a label value.
+fn label(value: &str) -> String {
+    let escaped = value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n");
+    format!("\"{escaped}\"")
+}
+
+/// Name, unit, help and value of a per-VM gauge.
+type Gauge = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&VmReport) -> String,
+);
+/// Name, help and value of a per-VM counter.
+type Counter = (&'static str, &'static str, fn(&VmCounters) -> u64);
+
+const GAUGES: [Gauge; 8] = [
+    (
+        "ghaf_mem_vm_balloon_size_bytes",
+        "bytes",
+        "Memory size of the guest as set by the balloon",
+        |report| report.stats.balloon_size.to_string(),
+    ),
+    (
+        "ghaf_mem_vm_base_memory_bytes",
+        "bytes",
+        "Memory of the guest outside of memory devices",
+        |report| report.stats.base_memory.to_string(),
+    ),
+    (
+        "ghaf_mem_vm_plugged_memory_bytes",
+        "bytes",
+        "Memory plugged into the guest by memory devices",
+        |report| report.stats.plugged_memory.to_string(),
+    ),
+    (
+        "ghaf_mem_vm_available_memory_bytes",
+        "bytes",
+        "Memory available to the guest as reported by it",
+        |report| report.stats.available_memory.to_string(),
+    ),
+    (
+        "ghaf_mem_vm_free_memory_bytes",
+        "bytes",
+        "Free memory of the guest as reported by it",
+        |report| report.stats.free_memory.to_string(),
+    ),
+    (
+        "ghaf_mem_vm_pressure_ratio",
+        "ratio",
+        "Share of the guest memory in use",
+        |report| (report.pressure as f64 / 100.).to_string(),
+    ),
+    (
+        "ghaf_mem_vm_wanted_bytes",
+        "bytes",
+        "Memory size the policy wants the guest to have, before host limits",
+        |report| report.wanted.to_string(),
+    ),
+    (
+        "ghaf_mem_vm_target_bytes",
+        "bytes",
+        "Memory size last requested for the guest",
+        |report| report.target.unwrap_or(report.size).to_string(),
+    ),
+];
+
+const COUNTERS: [Counter; 4] = [
+    (
+        "ghaf_mem_vm_inflations",
+        "Balloon inflations requested",
+        |c| c.inflations,
+    ),
+    (
+        "ghaf_mem_vm_deflations",
+        "Balloon deflations requested",
+        |c| c.deflations,
+    ),
+    (
+        "ghaf_mem_vm_qmp_errors",
+        "Failed QMP monitoring rounds",
+        |c| c.qmp_errors,
+    ),
+    ("ghaf_mem_vm_reconnects", "QMP reconnects", |c| c.reconnects),
+];
+
+/// Renders the state of the VMs in the OpenMetrics text format.
+///
+/// Gauges are left out for VMs that did not report stats yet.
+pub fn render(vms: &[VmStatus]) -> String {
+    let mut out = String::new();
+    for (name, unit, help, value) in GAUGES {
+        let _ = writeln!(out, "# TYPE {name} gauge");
+        let _ = writeln!(out, "# UNIT {name} {unit}");
+        let _ = writeln!(out, "# HELP {name} {help}");
+        for vm in vms {
+            if let Some(report) = &vm.report {
+                let _ = writeln!(out, "{name}{{vm={}}} {}", label(&vm.name), value(report));
+            }
+        }
+    }
+    for (name, help, value) in COUNTERS {
+        let _ = writeln!(out, "# TYPE {name} counter");
+        let _ = writeln!(out, "# HELP {name} {help}");
+        for vm in vms {
+            let _ = writeln!(
+                out,
+                "{name}_total{{vm={}}} {}",
+                label(&vm.name),
+                value(&vm.counters)
+            );
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+/// Listening socket of the metrics endpoint.
+pub enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+/// Creates the listening socket of the metrics endpoint.
+///
+/// A Unix socket left behind by an earlier run is replaced. Anyone may
+/// connect, so that scrapers running as other users can read the metrics.
+pub async fn bind(endpoint: &Endpoint) -> Result<Listener> {
+    let listener = match endpoint {
+        Endpoint::Unix(path) => Listener::Unix(
+            control::bind_socket(path, 0o666)
+                .with_context(|| format!("Failed to bind metrics socket {}", path.display()))?,
+        ),
+        Endpoint::Tcp(addr) => Listener::Tcp(
+            TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("Failed to bind metrics address {addr}"))?,
+        ),
+    };
+    info!("Serving metrics on {endpoint:?}");
+    Ok(listener)
+}
+
+/// Serves metrics requests until an error occurs.
+pub async fn serve(listener: Listener, manager: ManagerHandle) -> Result<()> {
+    loop {
+        let manager = manager.clone();
+        match &listener {
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                tokio::spawn(async move { serve_client(stream, &manager).await });
+            }
+            Listener::Tcp(listener) => {
+                let (stream, _) = listener.accept().await?;
+                tokio::spawn(async move { serve_client(stream, &manager).await });
+            }
+        }
+    }
+}
+
+async fn serve_client<S: AsyncRead + AsyncWrite + Unpin>(stream: S, manager: &ManagerHandle) {
+    if let Err(e) = respond(stream, manager).await {
+        debug!("Metrics client failed: {e}");
+    }
+}
+
+/// Answers a single HTTP request, the connection is closed afterwards.
+async fn respond<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    manager: &ManagerHandle,
+) -> Result<()> {
+    let mut stream = BufReader::new(stream);
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await?;
+    // Headers are not needed, but read so the client sees a clean close
+    let mut len = request_line.len();
+    let mut header = String::new();
+    loop {
+        header.clear();
+        len += stream.read_line(&mut header).await?;
+        ensure!(len <= MAX_REQUEST_LEN, "Request too long");
+        if header.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let (status, content_type, body) = match request(&request_line) {
+        Ok("/metrics") => ("200 OK", CONTENT_TYPE, render(&manager.status().await?)),
+        Ok(_) => ("404 Not Found", "text/plain", "Not found\n".into()),
+        Err(e) => ("400 Bad Request", "text/plain", format!("{e}\n")),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: {content_type}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {body}",
+        body.len()
+    );
+    let stream = stream.get_mut();
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Returns the path of a GET request.
+fn request(line: &str) -> Result<&str> {
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target), Some(version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        bail!("Malformed request");
+    };
+    ensure!(
+        version.starts_with("HTTP/1."),
+        "Unsupported protocol {version}"
+    );
+    ensure!(method == "GET", "Only GET is supported");
+    // The query string does not select anything
+    Ok(target.split('?').next().unwrap_or(target))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::MemoryStats;
+
+    #[test]
+    fn endpoints() {
+        assert_eq!(
+            "/run/metrics.sock".parse::<Endpoint>().unwrap(),
+            Endpoint::Unix("/run/metrics.sock".into())
+        );
+        assert_eq!(
+            "[::1]:9101".parse::<Endpoint>().unwrap(),
+            Endpoint::Tcp("[::1]:9101".parse().unwrap())
+        );
+        assert!("0.0.0.0:9101".parse::<Endpoint>().is_err());
+        assert!("metrics.sock".parse::<Endpoint>().is_err());
+    }
+
+    #[test]
+    fn openmetrics_text() {
+        let vms = [
+            VmStatus {
+                name: "gui-vm".into(),
+                paused: false,
+                pinned: None,
+                counters: VmCounters {
+                    inflations: 3,
+                    ..Default::default()
+                },
+                report: Some(VmReport {
+                    name: "gui-vm".into(),
+                    stats: MemoryStats {
+                        balloon_size: 4096,
+                        ..Default::default()
+                    },
+                    pressure: 75,
+                    size: 4096,
+                    wanted: 2048,
+                    target: Some(3072),
+                    saved: Default::default(),
+                }),
+            },
+            VmStatus {
+                name: "new\"vm".into(),
+                paused: false,
+                pinned: None,
+                counters: VmCounters::default(),
+                report: None,
+            },
+        ];
+        let text = render(&vms);
+        assert!(text.contains(
+            "# TYPE ghaf_mem_vm_balloon_size_bytes gauge\n\
+             # UNIT ghaf_mem_vm_balloon_size_bytes bytes\n\
+             # HELP ghaf_mem_vm_balloon_size_bytes Memory size of the guest as set by the balloon\n\
+             ghaf_mem_vm_balloon_size_bytes{vm=\"gui-vm\"} 4096\n\
+             # TYPE"
+        ));
+        assert!(text.contains("ghaf_mem_vm_pressure_ratio{vm=\"gui-vm\"} 0.75\n"));
+        assert!(text.contains("ghaf_mem_vm_wanted_bytes{vm=\"gui-vm\"} 2048\n"));
+        assert!(text.contains("ghaf_mem_vm_target_bytes{vm=\"gui-vm\"} 3072\n"));
+        assert!(text.contains(
+            "# TYPE ghaf_mem_vm_inflations counter\n\
+             # HELP ghaf_mem_vm_inflations Balloon inflations requested\n\
+             ghaf_mem_vm_inflations_total{vm=\"gui-vm\"} 3\n\
+             ghaf_mem_vm_inflations_total{vm=\"new\\\"vm\"} 0\n"
+        ));
+        assert!(text.ends_with("# EOF\n"));
+    }
+}
@@ -0,0 +1,62 @@
+/*
+ * Copyright 2025 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Policies deciding the memory size of a VM.
+//!
+//! A policy only sees the guest statistics and returns the size it wants the
+//! guest to have. Applying that size, the limits from the configuration and
+//! the host, and the time between changes are left to the manager, so
+//! policies can be tried out and tested without a VM.
+
+mod pid;
+mod threshold;
+mod trend;
+
+pub use pid::{Pid, PidTuning};
+pub use threshold::Threshold;
+pub use trend::Trend;
+
+use crate::{
+    config::{Policy, VmConfig},
+    stats::MemoryStats,
+};
+use std::collections::VecDeque;
+
+/// Memory size a policy wants the guest to have.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decision {
+    pub target: usize,
+    /// Why the size should change, for logging
+    pub reason: String,
+}
+
+/// Decides the memory size of a VM.
+pub trait BalloonPolicy: Send {
+    /// Returns the size the guest should have, or `None` to keep it as is.
+    ///
+    /// `history` holds the recent stats samples, oldest first and the
+    /// current one last. `size` is the current memory size of the guest as
+    /// seen by the backend.
+    fn decide(&mut self, history: &VecDeque<MemoryStats>, size: usize) -> Option<Decision>;
+}
+
+/// Creates the policy selected for a VM.
+pub fn from_config(config: &VmConfig) -> Box<dyn BalloonPolicy> {
+    match config.policy {
+        Policy::Threshold => Box::new(Threshold::new(config.low, config.high)),
+        Policy::Pid => Box::new(Pid::new(PidTuning {
+            target: config.target_pressure,
+            p: config.pid_p,
+            i: config.pid_i,
+            d: config.pid_d,
+            max_step: config.max_step,
+        })),
+        Policy::Trend => Box::new(Trend::new(
+            config.low,
+            config.high,
+            config.trend_horizon.as_secs_f64() / config.interval.as_secs_f64(),
+        )),
+    }
+}
@@ -0,0 +1,140 @@
+/*
+ * Copyright 2025 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+use super::{BalloonPolicy, Decision};
+use crate::stats::MemoryStats;
+use std::collections::VecDeque;
+
+/// Limit of the integral term, in percent of the memory size.
+const INTEGRAL_LIMIT: f32 = 50.;
+/// Changes smaller than this are not worth a resize.
+const MIN_STEP: usize = 8 * 1024 * 1024;
+
+/// Tuning of a [`Pid`] policy.
+#[derive(Debug, Clone, Copy)]
+pub struct PidTuning {
+    /// Pressure to aim for
+    pub target: u8,
+    /// Gains, the controller output is a change in percent of the current
+    /// memory size
+    pub p: f32,
+    pub i: f32,
+    pub d: f32,
+    /// Largest change of the memory size per decision
+    pub max_step: usize,
+}
+
+/// Steers the guest towards a target pressure with a PID controller.
+///
+/// Unlike [`super::Threshold`], which jumps between two sizes, the size is
+/// changed in steps proportional to how far and how fast the pressure is off
+/// the target, never more than `max_step` at once.
+///
+/// The integral is bounded and not accumulated while the step is capped, so
+/// it does not wind up during long phases the controller cannot correct.
+#[derive(Debug, Clone)]
+pub struct Pid {
+    tuning: PidTuning,
+    integral: f32,
+    last_error: Option<f32>,
+}
+
+impl Pid {
+    pub fn new(tuning: PidTuning) -> Self {
+        Self {
+            tuning,
+            integral: 0.,
+            last_error: None,
+        }
+    }
+}
+
+impl BalloonPolicy for Pid {
+    fn decide(&mut self, history: &VecDeque<MemoryStats>, size: usize) -> Option<Decision> {
+        let stats = history.back()?;
+        let tuning = &self.tuning;
+        let pressure = stats.pressure();
+        let error = pressure as f32 - tuning.target as f32;
+        let derivative = self.last_error.map_or(0., |last| error - last);
+        self.last_error = Some(error);
+
+        let integral = if tuning.i > 0. {
+            let limit = INTEGRAL_LIMIT / tuning.i;
+            (self.integral + error).clamp(-limit, limit)
+        } else {
+            0.
+        };
+        let output = tuning.p * error + tuning.i * integral + tuning.d * derivative;
+        let step = size as f64 * output as f64 / 100.;
+        let max_step = tuning.max_step as f64;
+        if step.abs() < max_step {
+            self.integral = integral;
+        }
+        let step = step.clamp(-max_step, max_step);
+
+        if (step.abs() as usize) < MIN_STEP {
+            return None;
+        }
+        Some(Decision {
+            target: (size as f64 + step).max(0.) as usize,
+            reason: format!("pressure {pressure}%, aiming for {}%", tuning.target),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: usize = 1024 * 1024;
+
+    const TUNING: PidTuning = PidTuning {
+        target: 75,
+        p: 0.5,
+        i: 0.05,
+        d: 0.2,
+        max_step: 256 * MIB,
+    };
+
+    /// Runs the controller against a guest using `reserved` MiB.
+    fn run(pid: &mut Pid, size: &mut usize, reserved: usize, samples: usize) -> Vec<usize> {
+        let mut history = VecDeque::new();
+        let mut sizes = vec![];
+        for _ in 0..samples {
+            history.push_back(MemoryStats {
+                balloon_size: *size,
+                total_memory: 4096 * MIB,
+                available_memory: size.saturating_sub(reserved * MIB),
+                ..Default::default()
+            });
+            if let Some(decision) = pid.decide(&history, *size) {
+                *size = decision.target;
+            }
+            sizes.push(*size);
+        }
+        sizes
+    }
+
+    #[test]
+    fn settles_at_target() {
+        let mut pid = Pid::new(TUNING);
+        let mut size = 4096 * MIB;
+        let sizes = run(&mut pid, &mut size, 1500, 100);
+        // 1500 MiB are 75% of 2000 MiB
+        assert!(size.abs_diff(2000 * MIB) < 40 * MIB, "{}", size / MIB);
+        for pair in sizes.windows(2) {
+            assert!(pair[0].abs_diff(pair[1]) <= TUNING.max_step);
+        }
+    }
+
+    #[test]
+    fn integral_does_not_wind_up() {
+        let mut pid = Pid::new(TUNING);
+        // Far too large for a long time, every step is capped
+        let mut size = 64 * 1024 * MIB;
+        run(&mut pid, &mut size, 100, 50);
+        assert_eq!(pid.integral, 0.);
+    }
+}
@@ -0,0 +1,78 @@
+/*
+ * Copyright 2025 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+use super::{BalloonPolicy, Decision};
+use crate::stats::MemoryStats;
+use std::collections::VecDeque;
+
+/// Inflates below the low and deflates above the high pressure limit.
+///
+/// Below `low` the guest is shrunk until its pressure rises to `low`. Above
+/// `high` it is grown to a pressure of `high - 2`, a bit below the limit so
+/// that the next sample does not trigger again right away. Only the current
+/// sample is looked at.
+#[derive(Debug, Clone)]
+pub struct Threshold {
+    low: u8,
+    high: u8,
+}
+
+impl Threshold {
+    /// `high` must be above 2 and `low` between 0 and `high`, as checked by
+    /// the configuration.
+    pub fn new(low: u8, high: u8) -> Self {
+        Self { low, high }
+    }
+}
+
+impl BalloonPolicy for Threshold {
+    fn decide(&mut self, history: &VecDeque<MemoryStats>, _size: usize) -> Option<Decision> {
+        let stats = history.back()?;
+        let pressure = stats.pressure();
+        if pressure < self.low {
+            Some(Decision {
+                target: stats.reserved() * 100 / self.low as usize,
+                reason: "pressure below limit".into(),
+            })
+        } else if pressure > self.high {
+            Some(Decision {
+                target: stats.reserved() * 100 / (self.high as usize - 2),
+                reason: "pressure above limit".into(),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: usize = 1024 * 1024;
+
+    fn decide(available: usize) -> Option<usize> {
+        let stats = MemoryStats {
+            balloon_size: 1000 * MIB,
+            total_memory: 1000 * MIB,
+            available_memory: available * MIB,
+            ..Default::default()
+        };
+        Threshold::new(70, 80)
+            .decide(&VecDeque::from([stats]), 1000 * MIB)
+            .map(|decision| decision.target / MIB)
+    }
+
+    #[test]
+    fn thresholds() {
+        // 40% pressure, shrink until 400 MiB reserved are 70%
+        assert_eq!(decide(600), Some(571));
+        // Between the limits
+        assert_eq!(decide(250), None);
+        // 90% pressure, grow until 900 MiB reserved are 78%
+        assert_eq!(decide(100), Some(1153));
+        assert_eq!(Threshold::new(70, 80).decide(&VecDeque::new(), 0), None);
+    }
+}
@@ -0,0 +1,124 @@
+/*
+ * Copyright 2025 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+use super::{BalloonPolicy, Decision, Threshold};
+use crate::stats::MemoryStats;
+use std::collections::VecDeque;
+
+/// Smoothing factor of the usage level.
+const LEVEL_ALPHA: f64 = 0.5;
+/// Smoothing factor of the growth rate.
+const RATE_ALPHA: f64 = 0.3;
+
+/// Threshold policy that looks ahead.
+///
+/// The memory used by the guest (reserved, i.e. not available) is smoothed
+/// with exponentially weighted averages of its level and its growth per
+/// sample. When the usage predicted `horizon` samples ahead would push the
+/// pressure above `high`, the guest is grown right away instead of waiting
+/// for the threshold to be crossed. For the same reason the guest is not
+/// shrunk while its usage is climbing towards the new size.
+#[derive(Debug, Clone)]
+pub struct Trend {
+    threshold: Threshold,
+    high: u8,
+    /// Prediction horizon in samples
+    horizon: f64,
+}
+
+impl Trend {
+    pub fn new(low: u8, high: u8, horizon: f64) -> Self {
+        Self {
+            threshold: Threshold::new(low, high),
+            high,
+            horizon,
+        }
+    }
+
+    /// Returns the smoothed usage and its growth per sample.
+    fn trend(history: &VecDeque<MemoryStats>) -> Option<(f64, f64)> {
+        let mut usage = history.iter().map(|stats| stats.reserved() as f64);
+        let mut level = usage.next()?;
+        let mut rate = 0.;
+        for used in usage {
+            let last = level;
+            level = LEVEL_ALPHA * used + (1. - LEVEL_ALPHA) * (level + rate);
+            rate = RATE_ALPHA * (level - last) + (1. - RATE_ALPHA) * rate;
+        }
+        Some((level, rate))
+    }
+}
+
+impl BalloonPolicy for Trend {
+    fn decide(&mut self, history: &VecDeque<MemoryStats>, size: usize) -> Option<Decision> {
+        let stats = history.back()?;
+        let decision = self.threshold.decide(history, size);
+        let (level, rate) = Self::trend(history)?;
+        if rate <= 0. {
+            return decision;
+        }
+        let predicted = (level + rate * self.horizon) as usize;
+
+        let high = self.high as usize;
+        if predicted * 100 > stats.balloon_size * high {
+            let target = predicted * 100 / (high - 2);
+            if target > size && decision.as_ref().is_none_or(|d| target > d.target) {
+                return Some(Decision {
+                    target,
+                    reason: format!(
+                        "usage growing by {} MiB per sample, pressure expected above limit",
+                        rate as usize / 1024 / 1024
+                    ),
+                });
+            }
+        }
+        // Shrinking would only have to be undone shortly
+        match decision {
+            Some(d) if d.target < size && predicted * 100 > d.target * high => None,
+            decision => decision,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: usize = 1024 * 1024;
+
+    fn history(used: &[usize]) -> VecDeque<MemoryStats> {
+        used.iter()
+            .map(|used| MemoryStats {
+                balloon_size: 1000 * MIB,
+                total_memory: 2000 * MIB,
+                available_memory: (1000 - used) * MIB,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn decide(used: &[usize]) -> Option<usize> {
+        Trend::new(50, 80, 5.)
+            .decide(&history(used), 1000 * MIB)
+            .map(|decision| decision.target / MIB)
+    }
+
+    #[test]
+    fn steady_usage_follows_thresholds() {
+        assert_eq!(decide(&[700, 700, 700]), None);
+        assert_eq!(decide(&[900, 900, 900]), Some(1153));
+        assert_eq!(decide(&[300, 300, 300]), Some(600));
+    }
+
+    #[test]
+    fn climbing_usage_deflates_early() {
+        // 70% is between the limits, but usage grows by 50 MiB per sample
+        let target = decide(&[450, 500, 550, 600, 650, 700]).unwrap();
+        assert!(target > 1000, "{target}");
+        // 45% is below the limits, but shrinking would soon overshoot
+        assert_eq!(decide(&[100, 200, 300, 400, 450]), None);
+        assert_eq!(decide(&[450, 450, 450, 450, 450]), Some(900));
+    }
+}
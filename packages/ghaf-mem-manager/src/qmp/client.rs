@@ -0,0 +1,229 @@
+/*
+ * Copyright 2025 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+use super::{commands::QmpCapabilities, Command, Error, Event, QmpError, Result};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufStream},
+    net::UnixStream,
+    sync::{broadcast, mpsc, oneshot},
+    task::JoinHandle,
+};
+use tracing::{debug, warn};
+
+type Reply = serde_json::Map<String, serde_json::Value>;
+
+/// Default time to wait for the reply to a command.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of events buffered for each subscriber.
+pub(super) const EVENT_QUEUE_LEN: usize = 64;
+
+struct Request {
+    id: u64,
+    line: Vec<u8>,
+    reply: oneshot::Sender<Reply>,
+}
+
+#[derive(Serialize)]
+struct Message {
+    execute: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    arguments: Option<serde_json::Value>,
+    id: u64,
+}
+
+/// A single QMP session on a Unix socket.
+///
+/// The socket is served by a background task for as long as the client
+/// lives. Commands may be executed concurrently from several futures, they
+/// are pipelined on the socket and each reply is routed back to its caller
+/// by the request `id`.
+///
+/// Every command is bounded by a timeout, so an unresponsive VM results in
+/// [`Error::Timeout`] instead of a caller waiting forever.
+pub struct QmpClient {
+    requests: mpsc::Sender<Request>,
+    events: broadcast::Sender<Event>,
+    next_id: AtomicU64,
+    timeout: Duration,
+    task: JoinHandle<()>,
+}
+
+impl QmpClient {
+    /// Connects to the QMP socket at `path` and negotiates capabilities.
+    ///
+    /// Commands time out after `timeout`, which also bounds the connection
+    /// handshake.
+    pub async fn connect<P: AsRef<Path>>(path: P, timeout: Duration) -> Result<Self> {
+        Self::connect_with_events(path, timeout, broadcast::channel(EVENT_QUEUE_LEN).0).await
+    }
+
+    /// Like [`QmpClient::connect`], but publishes events on an existing
+    /// channel, so subscriptions can outlive the connection.
+    pub(super) async fn connect_with_events<P: AsRef<Path>>(
+        path: P,
+        timeout: Duration,
+        events: broadcast::Sender<Event>,
+    ) -> Result<Self> {
+        let mut stream = BufStream::new(UnixStream::connect(path).await?);
+        let mut greeting = vec![];
+        tokio::time::timeout(timeout, stream.read_until(b'\n', &mut greeting))
+            .await
+            .map_err(|_| Error::Timeout {
+                command: "greeting",
+                timeout,
+            })??;
+        let greeting: serde_json::Value = serde_json::from_slice(&greeting)?;
+        if greeting.get("QMP").is_none() {
+            return Err(Error::Protocol("Invalid greeting".into()));
+        }
+
+        let (sender, receiver) = mpsc::channel(16);
+        let client = Self {
+            requests: sender,
+            events: events.clone(),
+            next_id: AtomicU64::new(0),
+            timeout,
+            task: tokio::spawn(Self::run(stream, receiver, events)),
+        };
+        client.execute(QmpCapabilities).await?;
+        Ok(client)
+    }
+
+    /// Subscribes to the asynchronous events sent by QEMU.
+    ///
+    /// A subscriber that falls behind loses the oldest events.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Returns `true` as long as the socket is open.
+    pub fn is_connected(&self) -> bool {
+        !self.requests.is_closed()
+    }
+
+    /// Executes `cmd` and waits for its reply.
+    pub async fn execute<C: Command>(&self, cmd: C) -> Result<C::Response> {
+        self.execute_with_timeout(cmd, self.timeout).await
+    }
+
+    /// Executes `cmd` and waits at most `timeout` for its reply.
+    ///
+    /// A QMP `error` reply is returned as [`Error::Qmp`].
+    pub async fn execute_with_timeout<C: Command>(
+        &self,
+        cmd: C,
+        timeout: Duration,
+    ) -> Result<C::Response> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let arguments = match serde_json::to_value(&cmd)? {
+            serde_json::Value::Null => None,
+            serde_json::Value::Object(args) if args.is_empty() => None,
+            args => Some(args),
+        };
+        let mut line = serde_json::to_vec(&Message {
+            execute: C::NAME,
+            arguments,
+            id,
+        })?;
+        line.push(b'\n');
+
+        let (tx, rx) = oneshot::channel();
+        let mut reply = tokio::time::timeout(timeout, async {
+            self.requests
+                .send(Request {
+                    id,
+                    line,
+                    reply: tx,
+                })
+                .await
+                .map_err(|_| Error::Disconnected)?;
+            rx.await.map_err(|_| Error::Disconnected)
+        })
+        .await
+        .map_err(|_| Error::Timeout {
+            command: C::NAME,
+            timeout,
+        })??;
+
+        if let Some(ret) = reply.remove("return") {
+            Ok(serde_json::from_value(ret)?)
+        } else if let Some(error) = reply.remove("error") {
+            Err(Error::Qmp {
+                command: C::NAME,
+                error: serde_json::from_value::<QmpError>(error)?,
+            })
+        } else {
+            Err(Error::Protocol(format!("Invalid reply to {}", C::NAME)))
+        }
+    }
+
+    /// Connection task.
+    ///
+    /// Writes queued requests to the socket and routes everything read back:
+    /// replies go to the caller waiting for the matching `id`, asynchronous
+    /// events are published to the subscribers. The task exits when the socket is
+    /// closed or the client is dropped.
+    async fn run(
+        mut stream: BufStream<UnixStream>,
+        mut requests: mpsc::Receiver<Request>,
+        events: broadcast::Sender<Event>,
+    ) {
+        let mut pending: HashMap<u64, oneshot::Sender<Reply>> = HashMap::new();
+        let mut buf = vec![];
+        loop {
+            tokio::select! {
+                req = requests.recv() => {
+                    let Some(Request { id, line, reply }) = req else { break; };
+                    if stream.write_all(&line).await.is_err() || stream.flush().await.is_err() {
+                        break;
+                    }
+                    // Forget callers that gave up waiting
+                    pending.retain(|_, tx| !tx.is_closed());
+                    pending.insert(id, reply);
+                },
+                len = stream.read_until(b'\n', &mut buf) => {
+                    if !matches!(len, Ok(len) if len > 0) {
+                        break;
+                    }
+                    let data = serde_json::from_slice(&buf);
+                    buf.clear();
+                    let Ok(serde_json::Value::Object(mut data)) = data else {
+                        warn!("Ignoring malformed QMP message");
+                        continue;
+                    };
+                    if data.contains_key("event") {
+                        if let Some(event) = Event::from_message(data) {
+                            debug!("Got event: {event:?}");
+                            // No subscribers is fine
+                            let _ = events.send(event);
+                        }
+                    } else if let Some(tx) = data
+                        .remove("id")
+                        .and_then(|id| id.as_u64())
+                        .and_then(|id| pending.remove(&id))
+                    {
+                        let _ = tx.send(data);
+                    } else {
+                        debug!("Ignoring unsolicited QMP reply: {data:?}");
+                    }
+                },
+            }
+        }
+    }
+}
+
+impl Drop for QmpClient {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
@@ -0,0 +1,216 @@
+/*
+ * Copyright 2025 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Typed QMP commands and their replies.
+
+use super::Command;
+use serde::{Deserialize, Serialize};
+use std::marker::PhantomData;
+
+/// QOM path of the balloon device in Ghaf VMs.
+pub const BALLOON_DEVICE: &str = "/machine/peripheral/balloon0";
+
+/// Reply of commands that do not return anything.
+#[derive(Deserialize, Debug)]
+pub struct Empty {}
+
+/// Leaves capabilities negotiation mode, sent once after connecting.
+#[derive(Serialize, Debug)]
+pub struct QmpCapabilities;
+
+impl Command for QmpCapabilities {
+    const NAME: &'static str = "qmp_capabilities";
+    type Response = Empty;
+}
+
+#[derive(Serialize, Debug)]
+pub struct QueryBalloon;
+
+impl Command for QueryBalloon {
+    const NAME: &'static str = "query-balloon";
+    type Response = BalloonInfo;
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct BalloonInfo {
+    /// Current size of the guest memory in bytes
+    pub actual: usize,
+}
+
+/// Requests the guest memory size to be changed to `value` bytes.
+#[derive(Serialize, Debug)]
+pub struct Balloon {
+    pub value: usize,
+}
+
+impl Command for Balloon {
+    const NAME: &'static str = "balloon";
+    type Response = Empty;
+}
+
+#[derive(Serialize, Debug)]
+pub struct QueryMemorySizeSummary;
+
+impl Command for QueryMemorySizeSummary {
+    const NAME: &'static str = "query-memory-size-summary";
+    type Response = MemoryInfo;
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct MemoryInfo {
+    pub base_memory: usize,
+    pub plugged_memory: usize,
+}
+
+/// Reads a QOM property, `T` is the type of its value.
+#[derive(Serialize, Debug)]
+pub struct QomGet<T> {
+    pub path: String,
+    pub property: String,
+    #[serde(skip)]
+    _value: PhantomData<fn() -> T>,
+}
+
+impl<T> QomGet<T> {
+    pub fn new<P: Into<String>, N: Into<String>>(path: P, property: N) -> Self {
+        Self {
+            path: path.into(),
+            property: property.into(),
+            _value: PhantomData,
+        }
+    }
+}
+
+impl<T: for<'a> Deserialize<'a>> Command for QomGet<T> {
+    const NAME: &'static str = "qom-get";
+    type Response = T;
+}
+
+/// Writes a QOM property.
+#[derive(Serialize, Debug)]
+pub struct QomSet<T> {
+    pub path: String,
+    pub property: String,
+    pub value: T,
+}
+
+impl<T> QomSet<T> {
+    pub fn new<P: Into<String>, N: Into<String>>(path: P, property: N, value: T) -> Self {
+        Self {
+            path: path.into(),
+            property: property.into(),
+            value,
+        }
+    }
+}
+
+impl<T: Serialize> Command for QomSet<T> {
+    const NAME: &'static str = "qom-set";
+    type Response = Empty;
+}
+
+/// Statistics reported by the guest balloon driver.
+///
+/// QEMU reports statistics the guest does not provide as -1, these are
+/// `None`. Sizes are in bytes, swap in/out are cumulative byte counts and
+/// the faults and hugetlb counters are cumulative event counts.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct GuestMemoryStats {
+    pub stat_available_memory: usize,
+    pub stat_free_memory: usize,
+    #[serde(default, deserialize_with = "deserialize_stat")]
+    pub stat_total_memory: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_stat")]
+    pub stat_disk_caches: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_stat")]
+    pub stat_swap_in: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_stat")]
+    pub stat_swap_out: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_stat")]
+    pub stat_major_faults: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_stat")]
+    pub stat_minor_faults: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_stat")]
+    pub stat_htlb_pgalloc: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_stat")]
+    pub stat_htlb_pgfail: Option<u64>,
+}
+
+fn deserialize_stat<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
+    // Unset statistics are -1, which may also arrive as its u64 representation
+    let value = i128::deserialize(d)?;
+    Ok(u64::try_from(value).ok().filter(|&value| value != u64::MAX))
+}
+
+/// Value of the `guest-stats` property of the balloon device.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct GuestMemoryInfo {
+    pub last_update: usize,
+    pub stats: GuestMemoryStats,
+}
+
+/// Reads the memory statistics reported by the guest balloon driver.
+pub fn query_guest_stats() -> QomGet<GuestMemoryInfo> {
+    QomGet::new(BALLOON_DEVICE, "guest-stats")
+}
+
+/// Sets how often the guest balloon driver reports statistics, in seconds.
+pub fn set_guest_stats_interval(secs: u64) -> QomSet<u64> {
+    QomSet::new(BALLOON_DEVICE, "guest-stats-polling-interval", secs)
+}
+
+/// QOM path of the memory device with the given `id`.
+pub fn peripheral_path(id: &str) -> String {
+    format!("/machine/peripheral/{id}")
+}
+
+/// Reads a size property, e.g. `block-size` or `max-size`, of a virtio-mem
+/// device.
+pub fn query_virtio_mem_size(id: &str, property: &str) -> QomGet<usize> {
+    QomGet::new(peripheral_path(id), property)
+}
+
+/// Asks the guest to grow or shrink the memory plugged through a virtio-mem
+/// device to `size` bytes.
+pub fn set_virtio_mem_requested_size(id: &str, size: usize) -> QomSet<usize> {
+    QomSet::new(peripheral_path(id), "requested-size", size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn guest_stats() {
+        let info: GuestMemoryInfo = serde_json::from_value(json!({
+            "last-update": 7,
+            "stats": {
+                "stat-available-memory": 2048,
+                "stat-free-memory": 1024,
+                "stat-total-memory": 4096,
+                "stat-swap-in": 0,
+                "stat-swap-out": 512,
+                "stat-major-faults": 18446744073709551615u64,
+                "stat-minor-faults": -1,
+            }
+        }))
+        .unwrap();
+        let stats = info.stats;
+        assert_eq!(stats.stat_available_memory, 2048);
+        assert_eq!(stats.stat_total_memory, Some(4096));
+        assert_eq!(
+            (stats.stat_swap_in, stats.stat_swap_out),
+            (Some(0), Some(512))
+        );
+        assert_eq!(stats.stat_major_faults, None);
+        assert_eq!(stats.stat_minor_faults, None);
+        assert_eq!(stats.stat_disk_caches, None);
+    }
+}
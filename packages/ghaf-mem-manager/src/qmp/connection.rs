@@ -0,0 +1,146 @@
+/*
+ * Copyright 2025 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+use super::{client::EVENT_QUEUE_LEN, Command, Error, Event, QmpClient, Result, DEFAULT_TIMEOUT};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+/// Initial delay before reconnecting to a QMP socket that went away.
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(1);
+/// Upper bound for the reconnect delay.
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+struct Backoff {
+    delay: Duration,
+    retry_at: Instant,
+}
+
+pub enum ConnectionState {
+    /// The existing connection is still up
+    Connected,
+    /// A new connection was established, per-connection setup must be redone
+    Reconnected,
+    /// Not connected, waiting for the next reconnect attempt
+    Down,
+}
+
+/// A QMP connection that stays open for its whole life.
+///
+/// When the socket goes away the connection is re-established by
+/// [`QmpConnection::ensure_connected`], with an exponential backoff between
+/// failed attempts. Event subscriptions are kept across reconnects.
+pub struct QmpConnection {
+    path: PathBuf,
+    client: Mutex<Option<Arc<QmpClient>>>,
+    events: broadcast::Sender<Event>,
+    backoff: Mutex<Backoff>,
+    timeout: Mutex<Duration>,
+}
+
+impl QmpConnection {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            client: Mutex::new(None),
+            events: broadcast::channel(EVENT_QUEUE_LEN).0,
+            backoff: Mutex::new(Backoff {
+                delay: RECONNECT_BACKOFF_MIN,
+                retry_at: Instant::now(),
+            }),
+            timeout: Mutex::new(DEFAULT_TIMEOUT),
+        }
+    }
+
+    /// Sets the time to wait for the reply to each command.
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.set_timeout(timeout);
+        self
+    }
+
+    /// Changes the command timeout, without reconnecting.
+    pub fn set_timeout(&self, timeout: Duration) {
+        *self.timeout.lock().unwrap() = timeout;
+    }
+
+    fn timeout(&self) -> Duration {
+        *self.timeout.lock().unwrap()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn client(&self) -> Option<Arc<QmpClient>> {
+        self.client.lock().unwrap().clone()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.client().is_some_and(|client| client.is_connected())
+    }
+
+    /// Makes sure the connection is up, reconnecting if the socket went away.
+    ///
+    /// Failed attempts are retried with an exponential backoff, in between
+    /// the connection is reported as down without touching the socket.
+    pub async fn ensure_connected(&self) -> ConnectionState {
+        if self.is_connected() {
+            return ConnectionState::Connected;
+        }
+        if self.client.lock().unwrap().take().is_some() {
+            warn!("Lost connection to {}", self.path.display());
+        }
+        if Instant::now() < self.backoff.lock().unwrap().retry_at {
+            return ConnectionState::Down;
+        }
+        let res =
+            QmpClient::connect_with_events(&self.path, self.timeout(), self.events.clone()).await;
+        let mut backoff = self.backoff.lock().unwrap();
+        match res {
+            Ok(client) => {
+                info!("Connected to {}", self.path.display());
+                *self.client.lock().unwrap() = Some(Arc::new(client));
+                backoff.delay = RECONNECT_BACKOFF_MIN;
+                ConnectionState::Reconnected
+            }
+            Err(e) => {
+                warn!(
+                    "Connection to {} failed: {e}, trying again in {}s",
+                    self.path.display(),
+                    backoff.delay.as_secs()
+                );
+                backoff.retry_at = Instant::now() + backoff.delay;
+                backoff.delay = (backoff.delay * 2).min(RECONNECT_BACKOFF_MAX);
+                ConnectionState::Down
+            }
+        }
+    }
+
+    /// Executes `cmd` on the current connection.
+    pub async fn execute<C: Command>(&self, cmd: C) -> Result<C::Response> {
+        self.execute_with_timeout(cmd, self.timeout()).await
+    }
+
+    /// Executes `cmd` on the current connection with a custom timeout.
+    pub async fn execute_with_timeout<C: Command>(
+        &self,
+        cmd: C,
+        timeout: Duration,
+    ) -> Result<C::Response> {
+        self.client()
+            .ok_or(Error::Disconnected)?
+            .execute_with_timeout(cmd, timeout)
+            .await
+    }
+
+    /// Subscribes to the events of the VM, across reconnects.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+}
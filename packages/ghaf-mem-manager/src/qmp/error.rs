@@ -0,0 +1,94 @@
+/*
+ * Copyright 2025 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+use serde::Deserialize;
+use std::{fmt, time::Duration};
+
+/// Error returned by QEMU in reply to a command.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct QmpError {
+    /// Error class, e.g. `GenericError` or `DeviceNotFound`
+    pub class: String,
+    /// Human readable description
+    pub desc: String,
+}
+
+impl fmt::Display for QmpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.class, self.desc)
+    }
+}
+
+impl std::error::Error for QmpError {}
+
+#[derive(Debug)]
+pub enum Error {
+    /// QEMU rejected the command
+    Qmp {
+        command: &'static str,
+        error: QmpError,
+    },
+    /// No reply was received in time
+    Timeout {
+        command: &'static str,
+        timeout: Duration,
+    },
+    /// The connection is down or was closed while waiting for the reply
+    Disconnected,
+    /// Unexpected data on the socket
+    Protocol(String),
+    Io(std::io::Error),
+    Json(serde_json::Error),
+}
+
+impl Error {
+    /// Returns the error reported by QEMU, if this is one.
+    pub fn qmp_error(&self) -> Option<&QmpError> {
+        match self {
+            Self::Qmp { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Qmp { command, error } => write!(f, "{command} failed: {error}"),
+            Self::Timeout { command, timeout } => {
+                write!(f, "{command} timed out after {}ms", timeout.as_millis())
+            }
+            Self::Disconnected => write!(f, "Not connected"),
+            Self::Protocol(msg) => write!(f, "QMP protocol error: {msg}"),
+            Self::Io(e) => write!(f, "QMP socket error: {e}"),
+            Self::Json(e) => write!(f, "Invalid QMP message: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Qmp { error, .. } => Some(error),
+            Self::Io(e) => Some(e),
+            Self::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Json(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
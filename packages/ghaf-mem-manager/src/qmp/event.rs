@@ -0,0 +1,108 @@
+/*
+ * Copyright 2025 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+use serde::Deserialize;
+
+/// Asynchronous QMP event.
+///
+/// Events the memory manager acts on are typed, everything else is passed
+/// through as [`Event::Other`].
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", content = "data", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Event {
+    /// The balloon size changed, `actual` is the new guest memory size
+    BalloonChange { actual: usize },
+    /// The guest was shut down, `guest` is set if the guest requested it
+    Shutdown { guest: bool, reason: String },
+    /// The guest was reset, `guest` is set if the guest requested it
+    Reset { guest: bool, reason: String },
+    /// The VM was paused
+    Stop,
+    /// The VM was resumed
+    Resume,
+    /// The guest kernel panicked
+    GuestPanicked { action: String },
+    /// A memory device, e.g. virtio-mem, changed its size
+    #[serde(rename_all = "kebab-case")]
+    MemoryDeviceSizeChange {
+        #[serde(default)]
+        id: Option<String>,
+        size: usize,
+        qom_path: String,
+    },
+    #[serde(skip)]
+    Other {
+        name: String,
+        data: serde_json::Value,
+    },
+}
+
+impl Event {
+    /// Parses an event message, unknown events are returned as
+    /// [`Event::Other`].
+    pub fn from_message(mut msg: serde_json::Map<String, serde_json::Value>) -> Option<Self> {
+        // Drop the timestamp, adjacently tagged enums only accept the tag
+        // and content fields
+        msg.remove("timestamp");
+        let msg = serde_json::Value::Object(msg);
+        serde_json::from_value(msg.clone()).ok().or_else(|| {
+            Some(Self::Other {
+                name: msg.get("event")?.as_str()?.to_owned(),
+                data: msg.get("data").cloned().unwrap_or_default(),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse(msg: serde_json::Value) -> Option<Event> {
+        let serde_json::Value::Object(msg) = msg else {
+            panic!("not an object");
+        };
+        Event::from_message(msg)
+    }
+
+    #[test]
+    fn typed_events() {
+        let timestamp = json!({"seconds": 1, "microseconds": 2});
+        assert_eq!(
+            parse(
+                json!({"event": "BALLOON_CHANGE", "data": {"actual": 1024}, "timestamp": timestamp})
+            ),
+            Some(Event::BalloonChange { actual: 1024 })
+        );
+        assert_eq!(
+            parse(json!({"event": "STOP", "timestamp": timestamp})),
+            Some(Event::Stop)
+        );
+        assert_eq!(
+            parse(
+                json!({"event": "MEMORY_DEVICE_SIZE_CHANGE", "timestamp": timestamp,
+                "data": {"id": "vmem0", "size": 2048, "qom-path": "/machine/peripheral/vmem0"}})
+            ),
+            Some(Event::MemoryDeviceSizeChange {
+                id: Some("vmem0".into()),
+                size: 2048,
+                qom_path: "/machine/peripheral/vmem0".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn unknown_events() {
+        assert_eq!(
+            parse(json!({"event": "RTC_CHANGE", "data": {"offset": 1}})),
+            Some(Event::Other {
+                name: "RTC_CHANGE".into(),
+                data: json!({"offset": 1}),
+            })
+        );
+        assert_eq!(parse(json!({"return": {}})), None);
+    }
+}
@@ -0,0 +1,50 @@
+/*
+ * Copyright 2025 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Asynchronous QMP client.
+//!
+//! Commands are typed: every request implements [`Command`], which names the
+//! QMP command and the type its `return` value deserializes into. Each request
+//! is sent with a unique `id`, so several commands can be in flight on the
+//! same connection at once and replies are matched to the right caller:
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use ghaf_mem_manager::qmp::{commands::*, QmpClient, DEFAULT_TIMEOUT};
+//!
+//! let client = QmpClient::connect("/run/vm.qmp", DEFAULT_TIMEOUT).await?;
+//! let (balloon, memory) = tokio::try_join!(
+//!     client.execute(QueryBalloon),
+//!     client.execute(QueryMemorySizeSummary),
+//! )?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Asynchronous events are parsed into [`Event`] and published to every
+//! subscriber, see [`QmpClient::subscribe`] and [`QmpConnection::subscribe`].
+
+mod client;
+pub mod commands;
+mod connection;
+mod error;
+mod event;
+
+pub use client::{QmpClient, DEFAULT_TIMEOUT};
+pub use connection::{ConnectionState, QmpConnection};
+pub use error::{Error, QmpError, Result};
+pub use event::Event;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+/// A QMP command.
+///
+/// The command arguments are the serialized fields of the implementing type.
+pub trait Command: Serialize {
+    /// Name of the command, as passed in `execute`
+    const NAME: &'static str;
+    /// Type of the `return` value of the command
+    type Response: DeserializeOwned;
+}
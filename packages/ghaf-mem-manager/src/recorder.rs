@@ -0,0 +1,294 @@
+/*
+ * Copyright 2025 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Recording of samples and decisions for tuning the policies.
+//!
+//! Every monitoring round that sampled or resized a VM is written as one
+//! [`Record`], either as JSON Lines or as CSV. The file is rotated when it
+//! would grow beyond its maximum size, keeping a number of older files as
+//! `path.1`, `path.2` and so on, with `path.1` the most recent.
+
+use crate::{
+    config::{RecordConfig, RecordFormat},
+    stats::MemoryStats,
+};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::OsString,
+    io::ErrorKind,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::mpsc,
+};
+use tracing::{info, warn};
+
+/// Records buffered before new ones are dropped.
+const RECORD_QUEUE_LEN: usize = 1024;
+
+const CSV_HEADER: &str = "timestamp,vm,balloon_size,base_memory,plugged_memory,\
+    total_memory,free_memory,available_memory,guest_total_memory,disk_caches,\
+    swap_in,swap_out,major_faults,minor_faults,hugetlb_allocations,\
+    hugetlb_failures,pressure,size,wanted,target,reason\n";
+
+/// One monitoring round of a VM.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    /// Seconds since the Unix epoch
+    pub timestamp: f64,
+    pub vm: String,
+    pub stats: MemoryStats,
+    pub pressure: u8,
+    /// Memory size of the guest when sampled
+    pub size: usize,
+    /// Size the VM should have, before host limits
+    pub wanted: usize,
+    /// Size requested in this round
+    pub target: Option<usize>,
+    /// Why the size was changed or held, if a decision was made
+    pub reason: Option<String>,
+}
+
+/// Current time for [`Record::timestamp`].
+pub fn timestamp() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+fn csv_option<T: ToString>(value: Option<T>) -> String {
+    value.map_or(String::new(), |v| v.to_string())
+}
+
+impl Record {
+    fn csv_row(&self) -> String {
+        let stats = &self.stats;
+        let fields = [
+            format!("{:.3}", self.timestamp),
+            csv_field(&self.vm),
+            stats.balloon_size.to_string(),
+            stats.base_memory.to_string(),
+            stats.plugged_memory.to_string(),
+            stats.total_memory.to_string(),
+            stats.free_memory.to_string(),
+            stats.available_memory.to_string(),
+            csv_option(stats.guest_total_memory),
+            csv_option(stats.disk_caches),
+            csv_option(stats.swap_in),
+            csv_option(stats.swap_out),
+            csv_option(stats.major_faults),
+            csv_option(stats.minor_faults),
+            csv_option(stats.hugetlb_allocations),
+            csv_option(stats.hugetlb_failures),
+            self.pressure.to_string(),
+            self.size.to_string(),
+            self.wanted.to_string(),
+            csv_option(self.target),
+            self.reason.as_deref().map_or(String::new(), csv_field),
+        ];
+        fields.join(",") + "\n"
+    }
+
+    fn format(&self, format: RecordFormat) -> Result<String> {
+        Ok(match format {
+            RecordFormat::JsonLines => serde_json::to_string(self)? + "\n",
+            RecordFormat::Csv => self.csv_row(),
+        })
+    }
+}
+
+/// Starts writing records in the background.
+///
+/// Write errors are logged and the records dropped, recording never holds
+/// up memory management.
+pub fn spawn(config: RecordConfig) -> mpsc::Sender<Record> {
+    let (sender, mut records) = mpsc::channel(RECORD_QUEUE_LEN);
+    tokio::spawn(async move {
+        info!("Recording samples to {}", config.path.display());
+        let mut writer = Writer::new(config);
+        let mut failing = false;
+        while let Some(record) = records.recv().await {
+            match writer.write(&record).await {
+                Ok(()) => failing = false,
+                Err(e) if !failing => {
+                    warn!("Failed to record samples: {e}");
+                    failing = true;
+                }
+                Err(_) => {}
+            }
+        }
+    });
+    sender
+}
+
+/// Appends records to the file, rotating it by size.
+struct Writer {
+    config: RecordConfig,
+    file: Option<File>,
+    size: usize,
+}
+
+impl Writer {
+    fn new(config: RecordConfig) -> Self {
+        Self {
+            config,
+            file: None,
+            size: 0,
+        }
+    }
+
+    async fn write(&mut self, record: &Record) -> Result<()> {
+        let line = record.format(self.config.format)?;
+        let mut file = match self.file.take() {
+            Some(file) => file,
+            None => self.open().await?,
+        };
+        // A file holding no records yet is never rotated
+        if self.size + line.len() > self.config.max_size && self.size > self.header().len() {
+            drop(file);
+            self.rotate().await?;
+            file = self.open().await?;
+        }
+        file.write_all(line.as_bytes()).await?;
+        file.flush().await?;
+        self.size += line.len();
+        self.file = Some(file);
+        Ok(())
+    }
+
+    fn header(&self) -> &'static str {
+        match self.config.format {
+            RecordFormat::Csv => CSV_HEADER,
+            RecordFormat::JsonLines => "",
+        }
+    }
+
+    /// Opens the file for appending and takes its size, a new CSV file
+    /// starts with the header.
+    async fn open(&mut self) -> Result<File> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.config.path)
+            .await?;
+        self.size = file.metadata().await?.len() as usize;
+        if self.size == 0 {
+            file.write_all(self.header().as_bytes()).await?;
+            self.size = self.header().len();
+        }
+        Ok(file)
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = OsString::from(&self.config.path);
+        path.push(format!(".{n}"));
+        path.into()
+    }
+
+    async fn rotate(&mut self) -> Result<()> {
+        let keep = self.config.keep;
+        let result = if keep == 0 {
+            fs::remove_file(&self.config.path).await
+        } else {
+            for n in (1..keep).rev() {
+                ignore_missing(fs::rename(self.rotated(n), self.rotated(n + 1)).await)?;
+            }
+            fs::rename(&self.config.path, self.rotated(1)).await
+        };
+        ignore_missing(result)?;
+        Ok(())
+    }
+}
+
+fn ignore_missing(result: std::io::Result<()>) -> std::io::Result<()> {
+    match result {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(reason: &str) -> Record {
+        Record {
+            timestamp: 1.5,
+            vm: "gui-vm".into(),
+            stats: MemoryStats {
+                balloon_size: 4096,
+                disk_caches: Some(10),
+                ..Default::default()
+            },
+            pressure: 50,
+            size: 4096,
+            wanted: 2048,
+            target: Some(2048),
+            reason: Some(reason.into()),
+        }
+    }
+
+    #[test]
+    fn csv_rows() {
+        let row = record("pressure below limit, \"low\"").csv_row();
+        assert_eq!(
+            row,
+            "1.500,gui-vm,4096,0,0,0,0,0,,10,,,,,,,50,4096,2048,2048,\
+             \"pressure below limit, \"\"low\"\"\"\n"
+        );
+        assert_eq!(
+            row.matches(',').count() - 1,
+            CSV_HEADER.matches(',').count()
+        );
+    }
+
+    #[tokio::test]
+    async fn rotates_by_size() {
+        let dir = std::env::temp_dir().join(format!("ghaf-mem-recorder-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("trace.csv");
+        let row_len = record("").csv_row().len();
+        let mut writer = Writer::new(RecordConfig {
+            path: path.clone(),
+            format: RecordFormat::Csv,
+            max_size: CSV_HEADER.len() + 2 * row_len,
+            keep: 2,
+        });
+        for _ in 0..7 {
+            writer.write(&record("")).await.unwrap();
+        }
+        let lines = |path: &PathBuf| {
+            let text = std::fs::read_to_string(path).unwrap();
+            assert!(text.starts_with(CSV_HEADER));
+            text.lines().count() - 1
+        };
+        // 7 rows in files of 2, the oldest file was dropped
+        assert_eq!(lines(&path), 1);
+        assert_eq!(lines(&writer.rotated(1)), 2);
+        assert_eq!(lines(&writer.rotated(2)), 2);
+        assert!(!writer.rotated(3).exists());
+
+        // A full file left by an earlier run is rotated before writing to it
+        writer.write(&record("")).await.unwrap();
+        let mut writer = Writer::new(writer.config.clone());
+        writer.write(&record("")).await.unwrap();
+        assert_eq!(lines(&path), 1);
+        assert_eq!(lines(&writer.rotated(1)), 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
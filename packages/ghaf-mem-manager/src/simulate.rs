@@ -0,0 +1,358 @@
+/*
+ * Copyright 2025 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Offline replay of guest memory demand against a policy.
+//!
+//! The guest is modelled by its demand alone: the available memory is the
+//! balloon size minus the demand, and a new balloon size takes effect right
+//! away. Running two policies on the same trace shows how often each of them
+//! resizes the guest and how much pressure is left.
+//!
+//! A trace is either a [recording](crate::recorder), where the demand is the
+//! memory reserved by the guest, or a CSV file with `time` and `demand`
+//! columns:
+//!
+//! ```text
+//! time,demand
+//! 0,1G
+//! 5,1536M
+//! ```
+
+use crate::{
+    config::{parse_size, VmConfig},
+    policy::{self, Decision},
+    state::VmState,
+    stats::MemoryStats,
+};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use serde::Deserialize;
+use std::{collections::BTreeSet, path::Path};
+
+/// Guest memory demand at one point of a trace.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    /// Seconds, from any starting point
+    pub time: f64,
+    pub vm: Option<String>,
+    pub demand: usize,
+    /// Memory of the guest, if recorded
+    pub memory: Option<usize>,
+}
+
+/// Line of a JSON Lines trace, a recording or a plain demand sample.
+#[derive(Deserialize)]
+struct JsonSample {
+    #[serde(alias = "timestamp")]
+    time: f64,
+    vm: Option<String>,
+    demand: Option<usize>,
+    stats: Option<MemoryStats>,
+}
+
+impl TryFrom<JsonSample> for Sample {
+    type Error = anyhow::Error;
+
+    fn try_from(sample: JsonSample) -> Result<Self> {
+        let (demand, memory) = match (sample.demand, sample.stats) {
+            (Some(demand), _) => (demand, None),
+            (None, Some(stats)) => (stats.reserved(), Some(stats.total_memory)),
+            (None, None) => bail!("Sample has neither demand nor stats"),
+        };
+        Ok(Self {
+            time: sample.time,
+            vm: sample.vm,
+            demand,
+            memory,
+        })
+    }
+}
+
+/// Splits a CSV line, with fields optionally in double quotes.
+fn split_csv(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+fn parse_csv(text: &str) -> Result<Vec<Sample>> {
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty());
+    let (_, header) = lines.next().context("Empty trace")?;
+    let header = split_csv(header);
+    let column = |names: &[&str]| header.iter().position(|c| names.contains(&c.trim()));
+    let time = column(&["time", "timestamp"]).context("Trace has no time column")?;
+    let vm = column(&["vm"]);
+    let demand = column(&["demand"]);
+    let reserved = column(&["balloon_size"]).zip(column(&["available_memory"]));
+    let memory = column(&["total_memory"]);
+    ensure!(
+        demand.is_some() || reserved.is_some(),
+        "Trace has neither a demand nor balloon_size and available_memory columns"
+    );
+
+    lines
+        .map(|(n, line)| {
+            let fields = split_csv(line);
+            let field = |i: usize| {
+                fields
+                    .get(i)
+                    .map(|f| f.trim())
+                    .ok_or_else(|| anyhow!("Missing column {}", header[i]))
+            };
+            let size = |i| field(i).and_then(|f| parse_size(f).map_err(|e| anyhow!(e)));
+            let sample = || -> Result<Sample> {
+                let demand = match (demand, reserved) {
+                    (Some(i), _) => size(i)?,
+                    (None, Some((balloon, available))) => {
+                        size(balloon)?.saturating_sub(size(available)?)
+                    }
+                    (None, None) => unreachable!(),
+                };
+                Ok(Sample {
+                    time: field(time)?.parse()?,
+                    vm: vm.map(field).transpose()?.map(str::to_owned),
+                    demand,
+                    memory: memory.map(size).transpose()?,
+                })
+            };
+            sample().with_context(|| format!("Invalid sample on line {}", n + 1))
+        })
+        .collect()
+}
+
+fn parse_json_lines(text: &str) -> Result<Vec<Sample>> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(n, line)| {
+            serde_json::from_str::<JsonSample>(line)
+                .map_err(anyhow::Error::from)
+                .and_then(Sample::try_from)
+                .with_context(|| format!("Invalid sample on line {}", n + 1))
+        })
+        .collect()
+}
+
+/// Parses a trace, keeping the samples of `vm`.
+///
+/// Without `vm` the trace must contain a single VM.
+pub fn parse_trace(text: &str, vm: Option<&str>) -> Result<Vec<Sample>> {
+    let mut samples = if text.trim_start().starts_with('{') {
+        parse_json_lines(text)?
+    } else {
+        parse_csv(text)?
+    };
+    if let Some(vm) = vm {
+        samples.retain(|sample| sample.vm.as_deref() == Some(vm));
+        ensure!(!samples.is_empty(), "Trace has no samples of {vm}");
+    } else {
+        let vms: BTreeSet<_> = samples.iter().filter_map(|s| s.vm.as_deref()).collect();
+        ensure!(
+            vms.len() <= 1,
+            "Trace has samples of several VMs, select one of {}",
+            vms.into_iter().collect::<Vec<_>>().join(", ")
+        );
+    }
+    ensure!(!samples.is_empty(), "Trace is empty");
+    ensure!(
+        samples.windows(2).all(|s| s[0].time <= s[1].time),
+        "Trace is not in time order"
+    );
+    Ok(samples)
+}
+
+pub fn load_trace(path: &Path, vm: Option<&str>) -> Result<Vec<Sample>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    parse_trace(&text, vm).with_context(|| format!("Invalid trace {}", path.display()))
+}
+
+/// State of the simulated guest at one sample.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    /// Seconds since the first sample
+    pub time: f64,
+    pub demand: usize,
+    /// Balloon size when sampled
+    pub size: usize,
+    pub pressure: u8,
+    /// Balloon operations so far, including one made at this sample
+    pub operations: usize,
+}
+
+/// Replays `trace` against the policy of `config`.
+///
+/// The guest has `memory` in total and starts with a balloon size of
+/// `size`. Like in the manager, the policy sees every sample but its
+/// decisions are only applied once the balloon interval has passed.
+pub fn simulate(trace: &[Sample], config: &VmConfig, memory: usize, size: usize) -> Vec<Step> {
+    let mut policy = policy::from_config(config);
+    let mut state = VmState::default();
+    let start = trace.first().map_or(0., |sample| sample.time);
+    let mut last_balloon = start;
+    let mut size = size.min(memory);
+    let mut operations = 0;
+    let mut steps = vec![];
+    for sample in trace {
+        let available = size.saturating_sub(sample.demand);
+        let stats = MemoryStats {
+            balloon_size: size,
+            base_memory: memory,
+            total_memory: memory,
+            available_memory: available,
+            free_memory: available,
+            ..Default::default()
+        };
+        let pressure = stats.pressure();
+        state.record(stats);
+        let sampled = size;
+        let can_balloon = sample.time - last_balloon > config.balloon_interval.as_secs_f64();
+        if let Some(Decision { target, .. }) = policy.decide(&state.history, size) {
+            let target = target.min(memory).clamp(config.minimum, config.maximum);
+            if can_balloon && target != size {
+                size = target;
+                operations += 1;
+                last_balloon = sample.time;
+            }
+        }
+        steps.push(Step {
+            time: sample.time - start,
+            demand: sample.demand,
+            size: sampled,
+            pressure,
+            operations,
+        });
+    }
+    steps
+}
+
+/// Figures to compare simulation runs by.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub samples: usize,
+    pub operations: usize,
+    pub mean_size: usize,
+    pub mean_pressure: f64,
+    pub max_pressure: u8,
+    /// Samples with a pressure above the high limit
+    pub above_high: usize,
+    /// Samples where the demand exceeded the balloon size
+    pub out_of_memory: usize,
+}
+
+impl Summary {
+    pub fn new(steps: &[Step], config: &VmConfig) -> Self {
+        let samples = steps.len().max(1);
+        Self {
+            samples: steps.len(),
+            operations: steps.last().map_or(0, |step| step.operations),
+            mean_size: steps.iter().map(|step| step.size).sum::<usize>() / samples,
+            mean_pressure: steps.iter().map(|step| step.pressure as f64).sum::<f64>()
+                / samples as f64,
+            max_pressure: steps.iter().map(|step| step.pressure).max().unwrap_or(0),
+            above_high: steps
+                .iter()
+                .filter(|step| step.pressure > config.high)
+                .count(),
+            out_of_memory: steps.iter().filter(|step| step.demand > step.size).count(),
+        }
+    }
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Samples: {}\n\
+             Balloon operations: {}\n\
+             Mean size: {} MiB\n\
+             Pressure: mean {:.1}%, max {}%\n\
+             Above high pressure: {} samples\n\
+             Out of memory: {} samples",
+            self.samples,
+            self.operations,
+            self.mean_size / 1024 / 1024,
+            self.mean_pressure,
+            self.max_pressure,
+            self.above_high,
+            self.out_of_memory,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, VmSettings};
+
+    const GIB: usize = 1024 * 1024 * 1024;
+
+    #[test]
+    fn traces() {
+        let plain = parse_trace("time,demand\n0,1G\n1.5, 2048M\n", None).unwrap();
+        assert_eq!(plain[1].time, 1.5);
+        assert_eq!(plain[1].demand, 2 * GIB);
+
+        let csv = "timestamp,vm,balloon_size,available_memory,total_memory,reason\n\
+                   1.0,a,4096,1024,8192,\"pressure below limit, \"\"x\"\"\"\n\
+                   1.0,b,4096,4096,8192,\n";
+        let recorded = parse_trace(csv, Some("a")).unwrap();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].demand, 3072);
+        assert_eq!(recorded[0].memory, Some(8192));
+        assert!(parse_trace(csv, None).is_err());
+
+        let json = r#"{"timestamp": 2.0, "vm": "a", "stats": {"balloon_size": 4096, "base_memory": 4096, "plugged_memory": 0, "total_memory": 4096, "free_memory": 0, "available_memory": 1024}}
+                      {"time": 3.0, "vm": "a", "demand": 10}"#;
+        let recorded = parse_trace(json, None).unwrap();
+        assert_eq!(recorded[0].demand, 3072);
+        assert_eq!(recorded[1].demand, 10);
+    }
+
+    #[test]
+    fn threshold_follows_demand() {
+        let config = Config::default()
+            .resolve_vm("vm", &VmSettings::default())
+            .unwrap();
+        // Idle, then busy for a while
+        let trace: Vec<_> = (0..60)
+            .map(|t| Sample {
+                time: t as f64,
+                vm: None,
+                demand: if (20..40).contains(&t) { 3 * GIB } else { GIB },
+                memory: None,
+            })
+            .collect();
+        let steps = simulate(&trace, &config, 4 * GIB, 4 * GIB);
+        // Shrunk once the balloon interval passed
+        assert_eq!(steps[3].operations, 0);
+        assert_eq!(steps[4].operations, 1);
+        assert_eq!(steps[5].size, GIB * 100 / 70);
+        // The guest cannot show more use than its size, so it is grown step
+        // by step while busy, and shrunk again when idle
+        assert!(steps[21].size < 3 * GIB);
+        assert!(steps[39].size >= 3 * GIB);
+        assert_eq!(steps[59].size, GIB * 100 / 70);
+        let summary = Summary::new(&steps, &config);
+        assert_eq!(summary.operations, 6);
+        assert_eq!(summary.out_of_memory, 9);
+    }
+}
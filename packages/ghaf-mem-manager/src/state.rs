@@ -0,0 +1,240 @@
+/*
+ * Copyright 2025 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Per-VM management state.
+//!
+//! Part of the state can be saved to a file, e.g. under `/run`, so that a
+//! restarted manager continues where the previous one stopped instead of
+//! resizing all VMs again.
+
+use crate::{backend::BALLOON_TOLERANCE, recorder::timestamp, stats::MemoryStats};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// Number of stats samples kept per VM.
+pub const HISTORY_LEN: usize = 60;
+
+/// Management state of a single VM.
+///
+/// The state is kept by VM identity rather than by connection, so it
+/// survives reconnects of the QMP socket.
+#[derive(Debug)]
+pub struct VmState {
+    /// `last-update` timestamp of the last guest stats sample acted on
+    pub last_update: Option<usize>,
+    /// When the last new guest stats sample arrived
+    pub last_sample: Instant,
+    /// Set while the guest stats are stale
+    pub stats_lost: bool,
+    /// When the balloon was last resized
+    pub last_balloon: Instant,
+    /// Recent stats samples, oldest first
+    pub history: VecDeque<MemoryStats>,
+    /// Balloon size requested but not yet confirmed by the guest
+    pub target: Option<usize>,
+    /// Set while the VM is stopped or crashed, it is not managed then
+    pub paused: bool,
+    /// Management suspended on request
+    pub user_paused: bool,
+    /// Size requested on request instead of following the policy
+    pub pinned: Option<usize>,
+}
+
+impl Default for VmState {
+    fn default() -> Self {
+        Self {
+            last_update: None,
+            last_sample: Instant::now(),
+            stats_lost: false,
+            last_balloon: Instant::now(),
+            history: VecDeque::with_capacity(HISTORY_LEN),
+            target: None,
+            paused: false,
+            user_paused: false,
+            pinned: None,
+        }
+    }
+}
+
+/// State of a VM kept across restarts of the manager.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SavedState {
+    /// Memory size of the VM when saved
+    pub size: usize,
+    pub target: Option<usize>,
+    /// When the balloon was last resized, in seconds since the Unix epoch
+    pub last_balloon: Option<f64>,
+    pub history: Vec<MemoryStats>,
+    pub user_paused: bool,
+    pub pinned: Option<usize>,
+}
+
+impl VmState {
+    /// Returns what is worth keeping of the state of a VM with `size`.
+    pub fn save(&self, size: usize) -> SavedState {
+        SavedState {
+            size,
+            target: self.target,
+            last_balloon: Some(timestamp() - self.last_balloon.elapsed().as_secs_f64()),
+            history: self.history.iter().cloned().collect(),
+            user_paused: self.user_paused,
+            pinned: self.pinned,
+        }
+    }
+
+    /// Takes over the pins and pauses requested in a previous run.
+    pub fn restore_requests(&mut self, saved: &SavedState) {
+        self.user_paused = saved.user_paused;
+        self.pinned = saved.pinned;
+    }
+
+    /// Continues from a previous run, now that the VM is known to have
+    /// `size`. The samples and the pending target are only taken over if
+    /// the VM was not resized in between.
+    ///
+    /// Returns `false` if the VM was resized.
+    pub fn resume(&mut self, saved: SavedState, size: usize) -> bool {
+        // The balloon interval applies across restarts either way
+        if let Some(last_balloon) = saved.last_balloon {
+            let elapsed =
+                Duration::try_from_secs_f64(timestamp() - last_balloon).unwrap_or_default();
+            self.last_balloon = Instant::now()
+                .checked_sub(elapsed)
+                .unwrap_or(self.last_balloon);
+        }
+        let matches = |expected: usize| expected.abs_diff(size) < BALLOON_TOLERANCE;
+        if !matches(saved.size) && !saved.target.is_some_and(matches) {
+            return false;
+        }
+        self.history = saved.history.into_iter().collect();
+        while self.history.len() > HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.target = saved.target.filter(|&target| !matches(target));
+        true
+    }
+
+    /// Records the timestamp of a guest stats sample.
+    ///
+    /// Returns `false` if the sample was seen already.
+    pub fn update_stats(&mut self, last_update: usize) -> bool {
+        if self.last_update == Some(last_update) {
+            return false;
+        }
+        self.last_update = Some(last_update);
+        self.last_sample = Instant::now();
+        true
+    }
+
+    pub fn record(&mut self, stats: MemoryStats) {
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(stats);
+    }
+
+    /// Forgets everything learned about the guest, e.g. after a reboot.
+    ///
+    /// What was requested by the user is kept.
+    pub fn reset(&mut self) {
+        *self = Self {
+            user_paused: self.user_paused,
+            pinned: self.pinned,
+            ..Self::default()
+        };
+    }
+}
+
+/// Contents of the state file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StateFile {
+    vms: BTreeMap<String, SavedState>,
+}
+
+/// Reads the state saved to `path`. A missing file is no state at all.
+pub fn load(path: &Path) -> Result<BTreeMap<String, SavedState>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let file: StateFile = serde_json::from_str(&text)
+        .with_context(|| format!("Invalid state file {}", path.display()))?;
+    Ok(file.vms)
+}
+
+/// Saves the state of the VMs to `path`, replacing the file at once so
+/// that it is never seen half written.
+pub async fn save(path: &Path, vms: BTreeMap<String, SavedState>) -> Result<()> {
+    let text = serde_json::to_string(&StateFile { vms })?;
+    let mut partial = PathBuf::from(path).into_os_string();
+    partial.push(".tmp");
+    tokio::fs::write(&partial, text)
+        .await
+        .with_context(|| format!("Failed to write {}", Path::new(&partial).display()))?;
+    tokio::fs::rename(&partial, path)
+        .await
+        .with_context(|| format!("Failed to replace {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicate_samples_are_skipped() {
+        let mut state = VmState::default();
+        assert!(state.update_stats(1));
+        assert!(!state.update_stats(1));
+        assert!(state.update_stats(2));
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let mut state = VmState::default();
+        for free_memory in 0..HISTORY_LEN + 10 {
+            state.record(MemoryStats {
+                free_memory,
+                ..Default::default()
+            });
+        }
+        assert_eq!(state.history.len(), HISTORY_LEN);
+        assert_eq!(state.history.front().unwrap().free_memory, 10);
+    }
+
+    #[test]
+    fn saved_state_is_checked_against_the_size() {
+        let mut state = VmState::default();
+        state.record(MemoryStats::default());
+        state.target = Some(2048 << 20);
+        state.pinned = Some(1024 << 20);
+        let saved = state.save(4096 << 20);
+        assert!(saved.last_balloon.is_some());
+
+        let mut restarted = VmState::default();
+        restarted.restore_requests(&saved);
+        assert_eq!(restarted.pinned, Some(1024 << 20));
+        // Target reached while the manager was not running
+        assert!(restarted.resume(saved.clone(), 2048 << 20));
+        assert_eq!(restarted.history.len(), 1);
+        assert_eq!(restarted.target, None);
+
+        let mut restarted = VmState::default();
+        assert!(restarted.resume(saved.clone(), 4096 << 20));
+        assert_eq!(restarted.target, Some(2048 << 20));
+
+        // Resized by someone else
+        let mut restarted = VmState::default();
+        assert!(!restarted.resume(saved, 3072 << 20));
+        assert!(restarted.history.is_empty());
+        assert_eq!(restarted.target, None);
+    }
+}
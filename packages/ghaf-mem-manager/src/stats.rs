@@ -0,0 +1,104 @@
+/*
+ * Copyright 2025 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Guest memory statistics.
+
+use crate::qmp::commands::{BalloonInfo, GuestMemoryInfo, MemoryInfo};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MemoryStats {
+    pub balloon_size: usize,
+    pub base_memory: usize,
+    pub plugged_memory: usize,
+    pub total_memory: usize,
+    pub free_memory: usize,
+    pub available_memory: usize,
+    // The statistics below are optional in the guest driver. Swap and
+    // fault counters are totals since the guest booted, compare samples
+    // of the history to get the activity.
+    /// Total memory as seen by the guest
+    pub guest_total_memory: Option<u64>,
+    pub disk_caches: Option<u64>,
+    /// Bytes swapped in
+    pub swap_in: Option<u64>,
+    /// Bytes swapped out
+    pub swap_out: Option<u64>,
+    pub major_faults: Option<u64>,
+    pub minor_faults: Option<u64>,
+    /// Successful hugetlb page allocations
+    pub hugetlb_allocations: Option<u64>,
+    pub hugetlb_failures: Option<u64>,
+}
+
+impl MemoryStats {
+    /// Combines the replies of the memory related QMP queries.
+    pub fn new(balloon: &BalloonInfo, memory: &MemoryInfo, guest: &GuestMemoryInfo) -> Self {
+        Self {
+            balloon_size: balloon.actual,
+            base_memory: memory.base_memory,
+            plugged_memory: memory.plugged_memory,
+            total_memory: memory.base_memory + memory.plugged_memory,
+            free_memory: guest.stats.stat_free_memory,
+            available_memory: guest.stats.stat_available_memory,
+            guest_total_memory: guest.stats.stat_total_memory,
+            disk_caches: guest.stats.stat_disk_caches,
+            swap_in: guest.stats.stat_swap_in,
+            swap_out: guest.stats.stat_swap_out,
+            major_faults: guest.stats.stat_major_faults,
+            minor_faults: guest.stats.stat_minor_faults,
+            hugetlb_allocations: guest.stats.stat_htlb_pgalloc,
+            hugetlb_failures: guest.stats.stat_htlb_pgfail,
+        }
+    }
+
+    pub fn pressure(&self) -> u8 {
+        (self.reserved() as f64 * 100. / self.balloon_size as f64).round() as u8
+    }
+
+    pub fn reserved(&self) -> usize {
+        self.balloon_size.saturating_sub(self.available_memory)
+    }
+}
+
+impl std::fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let mib =
+            |value: Option<u64>| value.map_or("n/a".into(), |v| format!("{} MiB", v / 1024 / 1024));
+        let count = |value: Option<u64>| value.map_or("n/a".into(), |v| v.to_string());
+        write!(
+            f,
+            "Memory stats:\n\
+             Balloon size: {} MiB\n\
+             Base memory: {} MiB\n\
+             Plugged memory: {} MiB\n\
+             Total memory: {} MiB\n\
+             Free memory: {} MiB\n\
+             Available memory: {} MiB\n\
+             Guest total memory: {}\n\
+             Disk caches: {}\n\
+             Swapped in: {}\n\
+             Swapped out: {}\n\
+             Major faults: {}\n\
+             Minor faults: {}\n\
+             Hugetlb allocations: {}\n\
+             Hugetlb failures: {}",
+            self.balloon_size / 1024 / 1024,
+            self.base_memory / 1024 / 1024,
+            self.plugged_memory / 1024 / 1024,
+            self.total_memory / 1024 / 1024,
+            self.free_memory / 1024 / 1024,
+            self.available_memory / 1024 / 1024,
+            mib(self.guest_total_memory),
+            mib(self.disk_caches),
+            mib(self.swap_in),
+            mib(self.swap_out),
+            count(self.major_faults),
+            count(self.minor_faults),
+            count(self.hugetlb_allocations),
+            count(self.hugetlb_failures),
+        )
+    }
+}
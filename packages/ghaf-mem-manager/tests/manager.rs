@@ -0,0 +1,291 @@
+/*
+ * Copyright 2025 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Management loop end to end, with the mock server in place of QEMU.
+
+mod mock;
+
+use ghaf_mem_manager::{
+    config::{Config, ShutdownConfig},
+    manager::{Manager, ManagerHandle, VmCommand, VmEvent, VmReport},
+    state,
+};
+use mock::{step, wait_for, Guest, MockQmp, GIB};
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sets up a manager for the VM behind `mock`, with host limits out of the
+/// way. `settings` are added to the VM.
+fn manager(mock: &MockQmp, settings: &str) -> Manager {
+    let config = Config::parse(&format!(
+        r#"
+        [host]
+        reserve = 0
+        critical = 0
+        pressure-limit = 100
+
+        [[vm]]
+        name = "test-vm"
+        socket = "{}"
+        balloon-interval = 1
+        {settings}
+        "#,
+        mock.path().display()
+    ))
+    .unwrap();
+    let (vms, host) = config
+        .resolve(&Default::default(), &Default::default())
+        .unwrap();
+    Manager::new(vms, host)
+}
+
+fn spawn(manager: Manager) -> ManagerHandle {
+    let handle = manager.handle();
+    tokio::spawn(manager.run());
+    handle
+}
+
+fn start(mock: &MockQmp, settings: &str) -> ManagerHandle {
+    spawn(manager(mock, settings))
+}
+
+/// Waits for the next `balloon` command and returns its size.
+async fn next_balloon(mock: &MockQmp) -> usize {
+    let sent = mock.commands("balloon").len();
+    wait_for(TIMEOUT, || {
+        let commands = mock.commands("balloon");
+        Some(commands.get(sent)?["value"].as_u64()? as usize)
+    })
+    .await
+}
+
+/// Waits until the report of the VM satisfies `check`.
+async fn wait_for_report(manager: &ManagerHandle, check: impl Fn(&VmReport) -> bool) {
+    tokio::time::timeout(TIMEOUT, async {
+        loop {
+            let status = manager.status().await.unwrap();
+            if status[0].report.as_ref().is_some_and(&check) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("Timed out waiting for the report");
+}
+
+#[tokio::test]
+async fn idle_guest_is_shrunk() {
+    let mock = MockQmp::start(Guest::new(4 * GIB, GIB));
+    let _manager = start(&mock, "");
+    // 25% pressure, shrunk so that the used memory is at the low limit
+    let size = next_balloon(&mock).await;
+    assert_eq!(size, GIB * 100 / 70);
+    assert_eq!(mock.guest().balloon, size);
+}
+
+#[tokio::test]
+async fn busy_guest_is_grown() {
+    let mut guest = Guest::new(4 * GIB, 1900 * mock::MIB);
+    guest.balloon = 2 * GIB;
+    let mock = MockQmp::start(guest);
+    let manager = start(&mock, "");
+    let size = next_balloon(&mock).await;
+    assert!(size > 2 * GIB, "{size}");
+
+    // The manager sees the new size once the guest confirmed it
+    wait_for_report(&manager, |report| {
+        report.size == size && report.target.is_none()
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn pinned_guest_and_lost_stats() {
+    let mock = MockQmp::start(Guest::new(4 * GIB, 2 * GIB));
+    let manager = start(&mock, "stats-timeout = 2\nmax-step = \"1G\"");
+    let mut events = manager.subscribe();
+
+    // Pins outside of the bounds of the VM are refused
+    let error = manager
+        .command(Some("test-vm".into()), VmCommand::Pin(Some(0)))
+        .await
+        .unwrap_err();
+    assert_eq!(error.to_string(), "test-vm cannot be pinned below 256 MiB");
+
+    manager
+        .command(Some("test-vm".into()), VmCommand::Pin(Some(3 * GIB)))
+        .await
+        .unwrap();
+    assert_eq!(next_balloon(&mock).await, 3 * GIB);
+    manager
+        .command(Some("test-vm".into()), VmCommand::Pin(None))
+        .await
+        .unwrap();
+
+    // The guest stops reporting, the manager grows it back to its full size
+    mock.script([step(|guest| guest.frozen = true)]);
+    let event = tokio::time::timeout(TIMEOUT, events.recv()).await.unwrap();
+    assert_eq!(
+        event.unwrap(),
+        VmEvent::StatsLost {
+            name: "test-vm".into()
+        }
+    );
+    wait_for(TIMEOUT, || (mock.guest().balloon == 4 * GIB).then_some(())).await;
+}
+
+#[tokio::test]
+async fn virtio_mem_guest_is_plugged() {
+    let mut guest = Guest::new(2 * GIB, 1900 * mock::MIB);
+    guest.virtio_mem = Some(4 * GIB);
+    let mock = MockQmp::start(guest);
+    let _manager = start(&mock, "backend = { virtio-mem = \"vmem0\" }");
+    let plugged = wait_for(TIMEOUT, || {
+        let commands = mock.commands("qom-set");
+        let request = commands
+            .iter()
+            .find(|args| args["property"] == "requested-size")?;
+        Some(request["value"].as_u64()? as usize)
+    })
+    .await;
+    assert!(plugged > 0 && plugged % (2 * mock::MIB) == 0, "{plugged}");
+    assert_eq!(mock.guest().plugged_memory, plugged);
+}
+
+#[tokio::test]
+async fn dry_run_leaves_the_guest_alone() {
+    let mock = MockQmp::start(Guest::new(4 * GIB, GIB));
+    let manager = start(&mock, "dry-run = true");
+    // The decision is made as usual
+    wait_for_report(&manager, |report| report.wanted == GIB * 100 / 70).await;
+    // but not applied, even after the balloon interval
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert!(mock.commands("balloon").is_empty());
+    assert_eq!(mock.guest().balloon, 4 * GIB);
+}
+
+#[tokio::test]
+async fn shutdown_restores_full_size() {
+    let mock = MockQmp::start(Guest::new(4 * GIB, GIB));
+    let manager = start(&mock, "");
+    manager
+        .command(Some("test-vm".into()), VmCommand::Pin(Some(2 * GIB)))
+        .await
+        .unwrap();
+    assert_eq!(next_balloon(&mock).await, 2 * GIB);
+
+    manager
+        .shutdown(ShutdownConfig {
+            restore: true,
+            timeout: TIMEOUT,
+        })
+        .await
+        .unwrap();
+    // Pins do not apply when exiting
+    assert_eq!(mock.guest().balloon, 4 * GIB);
+    assert!(manager.status().await.is_err());
+}
+
+#[tokio::test]
+async fn restart_continues_from_saved_state() {
+    let dir = std::env::temp_dir().join(format!("mem-manager-state-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("state.json");
+    let mock = MockQmp::start(Guest::new(4 * GIB, GIB));
+    let stop = ShutdownConfig {
+        restore: false,
+        timeout: TIMEOUT,
+    };
+
+    let first = spawn(manager(&mock, "").with_state_file(path.clone(), Default::default()));
+    first
+        .command(Some("test-vm".into()), VmCommand::Pin(Some(3 * GIB)))
+        .await
+        .unwrap();
+    assert_eq!(next_balloon(&mock).await, 3 * GIB);
+    wait_for_report(&first, |report| report.target.is_none()).await;
+    first.shutdown(stop.clone()).await.unwrap();
+
+    let saved = state::load(&path).unwrap();
+    assert_eq!(saved["test-vm"].pinned, Some(3 * GIB));
+    let second = spawn(manager(&mock, "").with_state_file(path, saved));
+    assert_eq!(second.status().await.unwrap()[0].pinned, Some(3 * GIB));
+    wait_for_report(&second, |report| !report.saved.history.is_empty()).await;
+    // Already at the pinned size, nothing to do
+    assert_eq!(mock.commands("balloon").len(), 1);
+    second.shutdown(stop).await.unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn restart_after_restoring() {
+    let dir = std::env::temp_dir().join(format!("mem-manager-restored-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("state.json");
+    let mock = MockQmp::start(Guest::new(4 * GIB, GIB));
+
+    let first = spawn(manager(&mock, "").with_state_file(path.clone(), Default::default()));
+    first
+        .command(Some("test-vm".into()), VmCommand::Pin(Some(3 * GIB)))
+        .await
+        .unwrap();
+    assert_eq!(next_balloon(&mock).await, 3 * GIB);
+    wait_for_report(&first, |report| report.target.is_none()).await;
+    first
+        .shutdown(ShutdownConfig {
+            restore: true,
+            timeout: TIMEOUT,
+        })
+        .await
+        .unwrap();
+    assert_eq!(mock.guest().balloon, 4 * GIB);
+
+    // Saved with the restored size
+    let saved = state::load(&path).unwrap();
+    assert_eq!(saved["test-vm"].size, 4 * GIB);
+    assert_eq!(saved["test-vm"].target, None);
+    // In a dry run, samples at the pinned size can only come from the history
+    let second = spawn(manager(&mock, "dry-run = true").with_state_file(path, saved));
+    wait_for_report(&second, |report| {
+        report
+            .saved
+            .history
+            .iter()
+            .any(|stats| stats.balloon_size == 3 * GIB)
+    })
+    .await;
+    second
+        .shutdown(ShutdownConfig {
+            restore: false,
+            timeout: TIMEOUT,
+        })
+        .await
+        .unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn evaluate_between_samples() {
+    // Between the low and high limit, the policy keeps the size
+    let mut guest = Guest::new(4 * GIB, 2600 * mock::MIB);
+    guest.balloon = 3500 * mock::MIB;
+    let mock = MockQmp::start(guest);
+    let manager = start(&mock, "interval = 5");
+    let mut events = manager.subscribe();
+    wait_for_report(&manager, |_| true).await;
+    // Past the first host check, so growth would not be capped
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+
+    // Long before the next sample of the guest
+    manager
+        .command(Some("test-vm".into()), VmCommand::Evaluate)
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert!(events.try_recv().is_err());
+    assert!(mock.commands("balloon").is_empty());
+}
@@ -0,0 +1,469 @@
+/*
+ * Copyright 2025 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Mock QMP server simulating a guest, for integration tests.
+//!
+//! The server listens on a temporary Unix socket and speaks enough QMP for
+//! the manager: the greeting and capabilities negotiation, the balloon and
+//! memory queries, guest stats and a virtio-mem device. The guest uses a
+//! fixed amount of memory, whatever is left of its balloon size is reported
+//! as available. Like QEMU, the guest stats are only updated once per
+//! polling interval, and a script changes the guest on every update. Tests
+//! can inject events, errors, late replies and disconnects at any time.
+
+// Every test binary includes this module but uses only parts of it
+#![allow(dead_code)]
+
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{unix::OwnedWriteHalf, UnixListener, UnixStream},
+    sync::broadcast,
+    task::JoinHandle,
+    time::Instant,
+};
+
+pub const MIB: usize = 1024 * 1024;
+pub const GIB: usize = 1024 * MIB;
+
+const BALLOON_DEVICE: &str = "/machine/peripheral/balloon0";
+/// QOM path of the virtio-mem device, with id `vmem0`
+pub const VIRTIO_MEM_DEVICE: &str = "/machine/peripheral/vmem0";
+const VIRTIO_MEM_BLOCK_SIZE: usize = 2 * MIB;
+
+/// State of the simulated guest.
+#[derive(Debug, Clone)]
+pub struct Guest {
+    /// Memory size as set by the balloon
+    pub balloon: usize,
+    pub base_memory: usize,
+    pub plugged_memory: usize,
+    /// Memory in use by the guest, the rest of the balloon size is available
+    pub used: usize,
+    /// The guest stops updating its stats, like a hung balloon driver
+    pub frozen: bool,
+    /// Maximum size of the virtio-mem device, `None` without one
+    pub virtio_mem: Option<usize>,
+    last_update: u64,
+}
+
+impl Guest {
+    /// A guest with `memory` of RAM, using `used` of it.
+    pub fn new(memory: usize, used: usize) -> Self {
+        Self {
+            balloon: memory,
+            base_memory: memory,
+            plugged_memory: 0,
+            used,
+            frozen: false,
+            virtio_mem: None,
+            last_update: 0,
+        }
+    }
+
+    pub fn available(&self) -> usize {
+        self.balloon.saturating_sub(self.used)
+    }
+
+    fn total_memory(&self) -> usize {
+        self.base_memory + self.plugged_memory
+    }
+}
+
+/// A change to the guest, applied when its stats are updated.
+pub type Step = Box<dyn FnOnce(&mut Guest) + Send>;
+
+pub fn step(f: impl FnOnce(&mut Guest) + Send + 'static) -> Step {
+    Box::new(f)
+}
+
+struct Shared {
+    guest: Guest,
+    script: VecDeque<Step>,
+    /// Errors returned instead of the next replies to a command
+    errors: HashMap<String, VecDeque<Value>>,
+    /// How long the next replies to a command are held back
+    delays: HashMap<String, VecDeque<Duration>>,
+    /// Guest stats polling interval, as set by the client
+    polling_interval: Duration,
+    /// When the guest stats were last updated
+    last_poll: Option<Instant>,
+    /// Commands received, with their arguments
+    commands: Vec<(String, Value)>,
+}
+
+type Reply = Result<Value, Value>;
+
+fn qmp_error(class: &str, desc: impl Into<String>) -> Value {
+    json!({"class": class, "desc": desc.into()})
+}
+
+/// Like QEMU, events without data have no `data` member.
+fn event(name: &str, data: Value) -> Value {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut event = json!({
+        "event": name,
+        "timestamp": {"seconds": now.as_secs(), "microseconds": now.subsec_micros()},
+    });
+    if !data.is_null() {
+        event["data"] = data;
+    }
+    event
+}
+
+impl Shared {
+    /// Executes a command, returning its reply and the event it causes.
+    fn execute(&mut self, command: &str, args: &Value) -> (Reply, Option<Value>) {
+        let guest = &mut self.guest;
+        let path = args["path"].as_str().unwrap_or_default();
+        let property = args["property"].as_str().unwrap_or_default();
+        let value = args["value"].as_u64().map(|value| value as usize);
+        let reply = match (command, path, property) {
+            ("qmp_capabilities", ..) => json!({}),
+            ("query-balloon", ..) => json!({"actual": guest.balloon}),
+            ("query-memory-size-summary", ..) => json!({
+                "base-memory": guest.base_memory,
+                "plugged-memory": guest.plugged_memory,
+            }),
+            ("balloon", ..) => {
+                let Some(value) = value else {
+                    return (Err(qmp_error("GenericError", "Missing value")), None);
+                };
+                // QEMU never grows the guest beyond its memory
+                guest.balloon = value.min(guest.total_memory());
+                let change = event("BALLOON_CHANGE", json!({"actual": guest.balloon}));
+                return (Ok(json!({})), Some(change));
+            }
+            ("qom-get", BALLOON_DEVICE, "guest-stats") => {
+                let due = self
+                    .last_poll
+                    .is_none_or(|last| last.elapsed() >= self.polling_interval);
+                if due {
+                    if let Some(step) = self.script.pop_front() {
+                        step(guest);
+                    }
+                    if !guest.frozen {
+                        guest.last_update += 1;
+                        self.last_poll = Some(Instant::now());
+                    }
+                }
+                json!({
+                    "last-update": guest.last_update,
+                    "stats": {
+                        "stat-available-memory": guest.available(),
+                        "stat-free-memory": guest.available() / 2,
+                        "stat-total-memory": guest.balloon,
+                        "stat-swap-in": 0,
+                        "stat-swap-out": 0,
+                        // Not provided by the guest
+                        "stat-disk-caches": -1,
+                        "stat-htlb-pgalloc": -1,
+                        "stat-htlb-pgfail": -1,
+                    },
+                })
+            }
+            ("qom-set", BALLOON_DEVICE, "guest-stats-polling-interval") => {
+                self.polling_interval = Duration::from_secs(value.unwrap_or_default() as u64);
+                json!({})
+            }
+            ("qom-get", VIRTIO_MEM_DEVICE, _) | ("qom-set", VIRTIO_MEM_DEVICE, _)
+                if guest.virtio_mem.is_none() =>
+            {
+                return (
+                    Err(qmp_error("DeviceNotFound", "Device 'vmem0' not found")),
+                    None,
+                );
+            }
+            ("qom-get", VIRTIO_MEM_DEVICE, "block-size") => json!(VIRTIO_MEM_BLOCK_SIZE),
+            ("qom-get", VIRTIO_MEM_DEVICE, "max-size") => json!(guest.virtio_mem),
+            ("qom-get", VIRTIO_MEM_DEVICE, "size") => json!(guest.plugged_memory),
+            ("qom-set", VIRTIO_MEM_DEVICE, "requested-size") => {
+                let max_size = guest.virtio_mem.unwrap_or_default();
+                match value {
+                    Some(value) if value <= max_size && value % VIRTIO_MEM_BLOCK_SIZE == 0 => {
+                        // The guest follows right away, keeping its balloon
+                        // at the full size
+                        guest.plugged_memory = value;
+                        guest.balloon = guest.total_memory();
+                        let change = event(
+                            "MEMORY_DEVICE_SIZE_CHANGE",
+                            json!({"id": "vmem0", "size": value, "qom-path": VIRTIO_MEM_DEVICE}),
+                        );
+                        return (Ok(json!({})), Some(change));
+                    }
+                    _ => {
+                        let error = qmp_error("GenericError", "Invalid requested-size");
+                        return (Err(error), None);
+                    }
+                }
+            }
+            ("qom-get" | "qom-set", ..) => {
+                let desc = format!("Property '{path}.{property}' not found");
+                return (Err(qmp_error("GenericError", desc)), None);
+            }
+            _ => {
+                let desc = format!("The command {command} has not been found");
+                return (Err(qmp_error("CommandNotFound", desc)), None);
+            }
+        };
+        (Ok(reply), None)
+    }
+}
+
+/// Mock QMP server, stopped and removed when dropped.
+pub struct MockQmp {
+    path: PathBuf,
+    shared: Arc<Mutex<Shared>>,
+    events: broadcast::Sender<Value>,
+    disconnect: broadcast::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl MockQmp {
+    /// Starts serving `guest` on a new socket, must be called within a
+    /// Tokio runtime.
+    pub fn start(guest: Guest) -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "ghaf-mem-mock-{}-{}.qmp",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).expect("Failed to bind mock QMP socket");
+        let shared = Arc::new(Mutex::new(Shared {
+            guest,
+            script: VecDeque::new(),
+            errors: HashMap::new(),
+            delays: HashMap::new(),
+            polling_interval: Duration::from_secs(1),
+            last_poll: None,
+            commands: vec![],
+        }));
+        let events = broadcast::channel(16).0;
+        let disconnect = broadcast::channel(1).0;
+        let task = tokio::spawn(accept(
+            listener,
+            shared.clone(),
+            events.clone(),
+            disconnect.clone(),
+        ));
+        Self {
+            path,
+            shared,
+            events,
+            disconnect,
+            task,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Current state of the guest.
+    pub fn guest(&self) -> Guest {
+        self.shared.lock().unwrap().guest.clone()
+    }
+
+    /// Changes the guest right away.
+    pub fn update(&self, f: impl FnOnce(&mut Guest)) {
+        f(&mut self.shared.lock().unwrap().guest);
+    }
+
+    /// Queues changes to the guest, one is applied on each guest stats
+    /// update.
+    pub fn script(&self, steps: impl IntoIterator<Item = Step>) {
+        self.shared.lock().unwrap().script.extend(steps);
+    }
+
+    /// Fails the next `command` with a QMP error.
+    pub fn fail_next(&self, command: &str, class: &str, desc: &str) {
+        self.shared
+            .lock()
+            .unwrap()
+            .errors
+            .entry(command.to_owned())
+            .or_default()
+            .push_back(qmp_error(class, desc));
+    }
+
+    /// Holds back the reply to the next `command` for `delay`, other
+    /// commands are answered in the meantime.
+    pub fn delay_next(&self, command: &str, delay: Duration) {
+        self.shared
+            .lock()
+            .unwrap()
+            .delays
+            .entry(command.to_owned())
+            .or_default()
+            .push_back(delay);
+    }
+
+    /// Sends an event to all connected clients, `data` is left out if
+    /// null.
+    pub fn event(&self, name: &str, data: Value) {
+        let _ = self.events.send(event(name, data));
+    }
+
+    /// Closes all client connections, the server keeps listening.
+    pub fn disconnect(&self) {
+        let _ = self.disconnect.send(());
+    }
+
+    /// Arguments of every `command` received so far.
+    pub fn commands(&self, command: &str) -> Vec<Value> {
+        self.shared
+            .lock()
+            .unwrap()
+            .commands
+            .iter()
+            .filter(|(name, _)| name == command)
+            .map(|(_, args)| args.clone())
+            .collect()
+    }
+}
+
+impl Drop for MockQmp {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Polls `check` until it returns a value, panicking after `timeout`.
+pub async fn wait_for<T>(timeout: Duration, mut check: impl FnMut() -> Option<T>) -> T {
+    tokio::time::timeout(timeout, async {
+        loop {
+            if let Some(value) = check() {
+                return value;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("Timed out waiting for the condition")
+}
+
+async fn accept(
+    listener: UnixListener,
+    shared: Arc<Mutex<Shared>>,
+    events: broadcast::Sender<Value>,
+    disconnect: broadcast::Sender<()>,
+) {
+    while let Ok((stream, _)) = listener.accept().await {
+        tokio::spawn(serve(
+            stream,
+            shared.clone(),
+            events.clone(),
+            disconnect.subscribe(),
+        ));
+    }
+}
+
+async fn send(writer: &mut OwnedWriteHalf, message: &Value) -> std::io::Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line).await
+}
+
+async fn serve(
+    stream: UnixStream,
+    shared: Arc<Mutex<Shared>>,
+    events: broadcast::Sender<Value>,
+    mut disconnect: broadcast::Receiver<()>,
+) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut subscription = events.subscribe();
+    let greeting = json!({
+        "QMP": {
+            "version": {"qemu": {"major": 9, "minor": 1, "micro": 0}, "package": ""},
+            "capabilities": ["oob"],
+        },
+    });
+    send(&mut writer, &greeting).await?;
+    let mut negotiated = false;
+    // Replies held back, with when they are due
+    let mut held: Vec<(Instant, Value, Option<Value>)> = vec![];
+    loop {
+        let due = held.iter().map(|(due, ..)| *due).min();
+        tokio::select! {
+            line = lines.next_line() => {
+                let Some(line) = line? else {
+                    return Ok(());
+                };
+                let request: Value = serde_json::from_str(&line)?;
+                let command = request["execute"].as_str().unwrap_or_default().to_owned();
+                let args = request.get("arguments").cloned().unwrap_or(json!({}));
+                let (reply, event, delay) = {
+                    let mut shared = shared.lock().unwrap();
+                    shared.commands.push((command.clone(), args.clone()));
+                    let delay = shared.delays.get_mut(&command).and_then(VecDeque::pop_front);
+                    let injected = shared
+                        .errors
+                        .get_mut(&command)
+                        .and_then(VecDeque::pop_front);
+                    let (reply, event) = if let Some(error) = injected {
+                        (Err(error), None)
+                    } else if !negotiated && command != "qmp_capabilities" {
+                        let desc = "Expecting capabilities negotiation with 'qmp_capabilities'";
+                        (Err(qmp_error("CommandNotFound", desc)), None)
+                    } else {
+                        shared.execute(&command, &args)
+                    };
+                    (reply, event, delay)
+                };
+                if command == "qmp_capabilities" && reply.is_ok() {
+                    negotiated = true;
+                }
+                let mut message = match reply {
+                    Ok(value) => json!({"return": value}),
+                    Err(error) => json!({"error": error}),
+                };
+                if let Some(id) = request.get("id") {
+                    message["id"] = id.clone();
+                }
+                if let Some(delay) = delay {
+                    held.push((Instant::now() + delay, message, event));
+                    continue;
+                }
+                send(&mut writer, &message).await?;
+                if let Some(event) = event {
+                    let _ = events.send(event);
+                }
+            },
+            _ = tokio::time::sleep_until(due.unwrap_or_else(Instant::now)), if due.is_some() => {
+                let now = Instant::now();
+                let (ready, later): (Vec<_>, Vec<_>) =
+                    held.drain(..).partition(|(due, ..)| *due <= now);
+                held = later;
+                for (_, message, event) in ready {
+                    send(&mut writer, &message).await?;
+                    if let Some(event) = event {
+                        let _ = events.send(event);
+                    }
+                }
+            },
+            Ok(event) = subscription.recv() => {
+                if negotiated {
+                    send(&mut writer, &event).await?;
+                }
+            },
+            _ = disconnect.recv() => return Ok(()),
+        }
+    }
+}
@@ -0,0 +1,133 @@
+/*
+ * Copyright 2025 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! QMP client against the mock server.
+
+mod mock;
+
+use ghaf_mem_manager::qmp::{
+    commands::{query_guest_stats, Balloon, QueryBalloon, QueryMemorySizeSummary},
+    ConnectionState, Error, Event, QmpConnection,
+};
+use mock::{step, wait_for, Guest, MockQmp, GIB};
+use serde_json::json;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn connect(mock: &MockQmp) -> QmpConnection {
+    let qmp = QmpConnection::new(mock.path());
+    assert!(matches!(
+        qmp.ensure_connected().await,
+        ConnectionState::Reconnected
+    ));
+    qmp
+}
+
+#[tokio::test]
+async fn commands_and_events() {
+    let mock = MockQmp::start(Guest::new(4 * GIB, GIB));
+    let qmp = connect(&mock).await;
+    let mut events = qmp.subscribe();
+    assert_eq!(mock.commands("qmp_capabilities").len(), 1);
+
+    let (balloon, memory) = tokio::try_join!(
+        qmp.execute(QueryBalloon),
+        qmp.execute(QueryMemorySizeSummary),
+    )
+    .unwrap();
+    assert_eq!(balloon.actual, 4 * GIB);
+    assert_eq!(memory.base_memory, 4 * GIB);
+
+    qmp.execute(Balloon { value: 3 * GIB }).await.unwrap();
+    assert_eq!(mock.commands("balloon"), [json!({"value": 3 * GIB})]);
+    assert_eq!(
+        events.recv().await.unwrap(),
+        Event::BalloonChange { actual: 3 * GIB }
+    );
+
+    mock.event("STOP", json!(null));
+    assert_eq!(events.recv().await.unwrap(), Event::Stop);
+}
+
+#[tokio::test]
+async fn guest_stats_follow_the_script() {
+    let mock = MockQmp::start(Guest::new(4 * GIB, GIB));
+    let qmp = connect(&mock).await;
+    mock.script([
+        step(|guest| guest.used = 2 * GIB),
+        step(|guest| guest.used = 3 * GIB),
+        step(|guest| guest.frozen = true),
+    ]);
+
+    let stats = qmp.execute(query_guest_stats()).await.unwrap();
+    assert_eq!(stats.last_update, 1);
+    assert_eq!(stats.stats.stat_available_memory, 2 * GIB);
+    assert_eq!(stats.stats.stat_disk_caches, None);
+    // Updated once per polling interval
+    let stats = qmp.execute(query_guest_stats()).await.unwrap();
+    assert_eq!(stats.last_update, 1);
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let stats = qmp.execute(query_guest_stats()).await.unwrap();
+    assert_eq!(stats.last_update, 2);
+    assert_eq!(stats.stats.stat_available_memory, GIB);
+    // A frozen guest keeps reporting the same sample
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let stats = qmp.execute(query_guest_stats()).await.unwrap();
+    assert_eq!(stats.last_update, 2);
+}
+
+#[tokio::test]
+async fn errors_and_reconnects() {
+    let mock = MockQmp::start(Guest::new(4 * GIB, GIB));
+    let qmp = connect(&mock).await;
+
+    mock.fail_next("query-balloon", "GenericError", "injected");
+    let error = qmp.execute(QueryBalloon).await.unwrap_err();
+    assert_eq!(error.qmp_error().unwrap().desc, "injected");
+    // Only the next command fails
+    qmp.execute(QueryBalloon).await.unwrap();
+
+    mock.disconnect();
+    wait_for(TIMEOUT, || (!qmp.is_connected()).then_some(())).await;
+    assert!(qmp.execute(QueryBalloon).await.is_err());
+    assert!(matches!(
+        qmp.ensure_connected().await,
+        ConnectionState::Reconnected
+    ));
+    qmp.execute(QueryBalloon).await.unwrap();
+    assert_eq!(mock.commands("qmp_capabilities").len(), 2);
+}
+
+#[tokio::test]
+async fn commands_time_out() {
+    let mock = MockQmp::start(Guest::new(4 * GIB, GIB));
+    let timeout = Duration::from_millis(500);
+    let qmp = QmpConnection::new(mock.path()).with_timeout(timeout);
+    qmp.ensure_connected().await;
+
+    mock.delay_next("query-balloon", Duration::from_millis(700));
+    let start = std::time::Instant::now();
+    let error = qmp.execute(QueryBalloon).await.unwrap_err();
+    assert!(
+        matches!(
+            error,
+            Error::Timeout {
+                command: "query-balloon",
+                ..
+            }
+        ),
+        "{error}"
+    );
+    assert!(start.elapsed() < Duration::from_millis(700));
+
+    // The late reply arrives while this command is waiting, it goes
+    // nowhere and the connection stays up
+    mock.delay_next("query-memory-size-summary", Duration::from_millis(400));
+    let memory = qmp.execute(QueryMemorySizeSummary).await.unwrap();
+    assert_eq!(memory.base_memory, 4 * GIB);
+    assert!(qmp.is_connected());
+    assert_eq!(qmp.execute(QueryBalloon).await.unwrap().actual, 4 * GIB);
+}